@@ -0,0 +1,178 @@
+//! Assembler errors, with where they are and an excerpt of the source to show it.
+
+use std::fmt;
+use std::fmt::Write;
+
+use super::preprocess::Location;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    /// The file the error is in, unless the source was given as a string.
+    pub file: Option<String>,
+    /// Source line, counting from 1.
+    pub line: usize,
+    /// Column on the line, counting from 1, when the error can be pinned down to one.
+    pub column: Option<usize>,
+    pub message: String,
+    /// The line as written.
+    pub source: Option<String>,
+}
+
+impl AsmError {
+    pub(super) fn new(line: usize, message: String) -> Self {
+        AsmError {
+            file: None,
+            line,
+            column: None,
+            message,
+            source: None,
+        }
+    }
+
+    pub(super) fn at(location: &Location, column: Option<usize>, message: String) -> Self {
+        AsmError {
+            file: location.file.as_ref().map(|file| file.to_string()),
+            line: location.line,
+            column,
+            message,
+            source: Some(location.source.to_string()),
+        }
+    }
+
+    /// The source line with a caret under the column, or `None` if the line is not known.
+    pub fn excerpt(&self) -> Option<String> {
+        let source = self.source.as_ref()?;
+        let mut excerpt = format!("    {}", source.trim_end());
+        if let Some(column) = self.column {
+            // tabs are kept so that the caret lines up however wide they are shown
+            let indent: String = source
+                .chars()
+                .take(column - 1)
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            let _ = write!(excerpt, "\n    {}^", indent);
+        }
+        Some(excerpt)
+    }
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{}:{}", file, self.line)?,
+            None => write!(f, "line {}", self.line)?,
+        }
+        if let Some(column) = self.column {
+            write!(f, ":{}", column)?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+/// Every error found in a program, in source order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostics {
+    pub errors: Vec<AsmError>,
+}
+
+impl Diagnostics {
+    /// The errors as a JSON array, for editors. Each error is an object with `file` (`null`
+    /// when assembling a string), `line`, `column` (`null` when unknown) and `message`.
+    pub fn to_json(&self) -> String {
+        let mut json = String::from("[");
+        for (index, error) in self.errors.iter().enumerate() {
+            if index > 0 {
+                json.push(',');
+            }
+            let _ = write!(
+                json,
+                "{{\"file\":{},\"line\":{},\"column\":{},\"message\":{}}}",
+                error
+                    .file
+                    .as_ref()
+                    .map_or("null".to_string(), |f| json_string(f)),
+                error.line,
+                error.column.map_or("null".to_string(), |c| c.to_string()),
+                json_string(&error.message)
+            );
+        }
+        json.push(']');
+        json
+    }
+}
+
+impl From<AsmError> for Diagnostics {
+    fn from(error: AsmError) -> Self {
+        Diagnostics {
+            errors: vec![error],
+        }
+    }
+}
+
+/// One error per paragraph, each with its excerpt.
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, error) in self.errors.iter().enumerate() {
+            if index > 0 {
+                writeln!(f)?;
+            }
+            writeln!(f, "error: {}", error)?;
+            if let Some(excerpt) = error.excerpt() {
+                writeln!(f, "{}", excerpt)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Diagnostics {}
+
+fn json_string(text: &str) -> String {
+    let mut json = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+#[test]
+fn test_rendering() {
+    let location = Location {
+        file: Some("a \"b\".asm".into()),
+        line: 3,
+        source: "\tADD R0, R0, #16 ; add".into(),
+    };
+    let error = AsmError::at(&location, Some(14), "immediate 16 does not fit".to_string());
+    assert_eq!(
+        error.to_string(),
+        "a \"b\".asm:3:14: immediate 16 does not fit"
+    );
+    assert_eq!(
+        error.excerpt().unwrap(),
+        "    \tADD R0, R0, #16 ; add\n    \t            ^"
+    );
+    let diagnostics = Diagnostics {
+        errors: vec![error, AsmError::new(1, "missing .ORIG".to_string())],
+    };
+    assert_eq!(
+        diagnostics.to_string(),
+        "error: a \"b\".asm:3:14: immediate 16 does not fit\n    \tADD R0, R0, #16 ; add\n    \t            ^\n\nerror: line 1: missing .ORIG\n"
+    );
+    assert_eq!(
+        diagnostics.to_json(),
+        "[{\"file\":\"a \\\"b\\\".asm\",\"line\":3,\"column\":14,\"message\":\"immediate 16 does not fit\"},\
+         {\"file\":null,\"line\":1,\"column\":null,\"message\":\"missing .ORIG\"}]"
+    );
+}
@@ -0,0 +1,249 @@
+//! Constant expressions in operands, such as `LABEL+2`, `'A'` or `x10*2`.
+
+use std::collections::HashMap;
+
+use super::lexer::{Token, TokenKind};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Number(i32),
+    Symbol(String),
+    Neg(Box<Expr>),
+    Binary(char, Box<Expr>, Box<Expr>),
+}
+
+/// The value of an expression. An address is anything that depends on a label, so that PC
+/// offsets can tell `BR LOOP` from `BR #-3`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Value {
+    pub value: i32,
+    pub address: bool,
+    /// For an address in another module, the index of its `.EXTERNAL` symbol. `value` is
+    /// then the offset from that symbol.
+    pub external: Option<usize>,
+}
+
+impl Value {
+    pub fn number(value: i32) -> Self {
+        Value {
+            value,
+            address: false,
+            external: None,
+        }
+    }
+
+    pub fn address(value: u16) -> Self {
+        Value {
+            value: i32::from(value),
+            address: true,
+            external: None,
+        }
+    }
+
+    pub fn external(index: usize) -> Self {
+        Value {
+            value: 0,
+            address: true,
+            external: Some(index),
+        }
+    }
+}
+
+/// Parses an expression from the start of `tokens`. Returns it with the number of tokens it
+/// took, or the column and reason it could not be parsed.
+pub fn parse(tokens: &[Token]) -> Result<(Expr, usize), (usize, String)> {
+    let mut parser = Parser { tokens, next: 0 };
+    let expr = parser.sum()?;
+    Ok((expr, parser.next))
+}
+
+struct Parser<'a> {
+    tokens: &'a [Token],
+    next: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn operator(&mut self, operators: &str) -> Option<char> {
+        match self.tokens.get(self.next) {
+            Some(Token {
+                kind: TokenKind::Operator(c),
+                ..
+            }) if operators.contains(*c) => {
+                self.next += 1;
+                Some(*c)
+            }
+            _ => None,
+        }
+    }
+
+    fn sum(&mut self) -> Result<Expr, (usize, String)> {
+        let mut expr = self.product()?;
+        while let Some(op) = self.operator("+-") {
+            expr = Expr::Binary(op, Box::new(expr), Box::new(self.product()?));
+        }
+        Ok(expr)
+    }
+
+    fn product(&mut self) -> Result<Expr, (usize, String)> {
+        let mut expr = self.unary()?;
+        while let Some(op) = self.operator("*/") {
+            expr = Expr::Binary(op, Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr, (usize, String)> {
+        if self.operator("-").is_some() {
+            return Ok(Expr::Neg(Box::new(self.unary()?)));
+        }
+        if self.operator("+").is_some() {
+            return self.unary();
+        }
+        let token = match self.tokens.get(self.next) {
+            Some(token) => token,
+            None => {
+                let column = self.tokens.last().map_or(1, |token| token.column + 1);
+                return Err((column, "expected an operand".to_string()));
+            }
+        };
+        self.next += 1;
+        match &token.kind {
+            TokenKind::Number(value) => Ok(Expr::Number(*value)),
+            TokenKind::Word(word) if super::is_label(word) => Ok(Expr::Symbol(word.clone())),
+            TokenKind::Operator('(') => {
+                let expr = self.sum()?;
+                match self.operator(")") {
+                    Some(_) => Ok(expr),
+                    None => Err((token.column, "unbalanced parenthesis".to_string())),
+                }
+            }
+            _ => Err((token.column, "expected a number or a label".to_string())),
+        }
+    }
+}
+
+impl Expr {
+    /// Evaluates the expression, looking symbols up with `lookup`.
+    pub fn eval<F>(&self, lookup: &F) -> Result<Value, String>
+    where
+        F: Fn(&str) -> Option<Value>,
+    {
+        match self {
+            Expr::Number(value) => Ok(Value::number(*value)),
+            Expr::Symbol(name) => lookup(name).ok_or_else(|| format!("undefined label `{}`", name)),
+            Expr::Neg(expr) => {
+                let value = expr.eval(lookup)?;
+                if value.address {
+                    return Err("cannot negate an address".to_string());
+                }
+                match value.value.checked_neg() {
+                    Some(value) => Ok(Value::number(value)),
+                    None => Err("`-` overflows".to_string()),
+                }
+            }
+            Expr::Binary(op, left, right) => {
+                let (left, right) = (left.eval(lookup)?, right.eval(lookup)?);
+                let value = match op {
+                    '+' => left.value.checked_add(right.value),
+                    '-' => left.value.checked_sub(right.value),
+                    '*' => left.value.checked_mul(right.value),
+                    _ if right.value == 0 => return Err("division by zero".to_string()),
+                    _ => left.value.checked_div(right.value),
+                };
+                let value = match value {
+                    Some(value) => value,
+                    None => return Err(format!("`{}` overflows", op)),
+                };
+                // an address plus or minus a number is an address, the distance between
+                // two addresses is a number
+                let (address, external) = match (op, left.address, right.address) {
+                    (_, false, false) => (false, None),
+                    ('+', true, false) | ('-', true, false) => (true, left.external),
+                    ('+', false, true) => (true, right.external),
+                    ('-', true, true) if left.external == right.external => (false, None),
+                    ('-', true, true) => {
+                        return Err("cannot subtract addresses in different modules".to_string())
+                    }
+                    _ => return Err(format!("cannot use `{}` on an address", op)),
+                };
+                Ok(Value {
+                    value,
+                    address,
+                    external,
+                })
+            }
+        }
+    }
+
+    /// Replaces the symbols in `values` by their value.
+    pub fn substitute(&mut self, values: &HashMap<String, i32>) {
+        match self {
+            Expr::Symbol(name) => {
+                if let Some(&value) = values.get(name.as_str()) {
+                    *self = Expr::Number(value);
+                }
+            }
+            Expr::Neg(expr) => expr.substitute(values),
+            Expr::Binary(_, left, right) => {
+                left.substitute(values);
+                right.substitute(values);
+            }
+            Expr::Number(_) => {}
+        }
+    }
+}
+
+#[test]
+fn test_expressions() {
+    let eval = |text: &str| -> Result<Value, String> {
+        let tokens = super::lexer::tokenize(text).unwrap();
+        let (expr, used) = parse(&tokens).map_err(|(_, message)| message)?;
+        assert_eq!(used, tokens.len());
+        expr.eval(&|name| match name {
+            "START" => Some(Value::address(0x3000)),
+            "END" => Some(Value::address(0x3010)),
+            "SIZE" => Some(Value::number(4)),
+            "PRINT" => Some(Value::external(0)),
+            _ => None,
+        })
+    };
+    assert_eq!(eval("x10*2"), Ok(Value::number(32)));
+    assert_eq!(eval("'A'+1"), Ok(Value::number(66)));
+    assert_eq!(eval("1+2*3"), Ok(Value::number(7)));
+    assert_eq!(eval("(1+2)*-3"), Ok(Value::number(-9)));
+    assert_eq!(eval("START+2"), Ok(Value::address(0x3002)));
+    assert_eq!(eval("START+SIZE*2"), Ok(Value::address(0x3008)));
+    assert_eq!(eval("END-START"), Ok(Value::number(16)));
+    assert_eq!(
+        eval("START*2"),
+        Err("cannot use `*` on an address".to_string())
+    );
+    assert_eq!(eval("1/0"), Err("division by zero".to_string()));
+    assert_eq!(eval("(x8000*x8000*2)/-1"), Err("`*` overflows".to_string()));
+    assert_eq!(
+        eval("(-x8000*x8000*2)/-1"),
+        Err("`/` overflows".to_string())
+    );
+    assert_eq!(eval("x4000*x4000*x4000"), Err("`*` overflows".to_string()));
+    assert_eq!(
+        eval("x4000*x4000*4+x4000*x4000*4"),
+        Err("`+` overflows".to_string())
+    );
+    assert_eq!(
+        eval("NOWHERE"),
+        Err("undefined label `NOWHERE`".to_string())
+    );
+    assert_eq!(eval("(1+2"), Err("unbalanced parenthesis".to_string()));
+    assert_eq!(
+        eval("PRINT+3"),
+        Ok(Value {
+            value: 3,
+            address: true,
+            external: Some(0),
+        })
+    );
+    assert_eq!(
+        eval("PRINT-START"),
+        Err("cannot subtract addresses in different modules".to_string())
+    );
+}
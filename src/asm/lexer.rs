@@ -0,0 +1,230 @@
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TokenKind {
+    /// A label, opcode, directive or register name, as written.
+    Word(String),
+    /// `#10`, `#-3`, `x3000`, `'A'`, or a plain decimal.
+    Number(i32),
+    /// A string literal with its escapes resolved.
+    Str(String),
+    /// One of `+ - * / ( )`.
+    Operator(char),
+    Comma,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    pub kind: TokenKind,
+    /// Column of the first character, counting from 1.
+    pub column: usize,
+}
+
+/// A malformed token, at `column`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LexError {
+    pub column: usize,
+    pub message: String,
+}
+
+const OPERATORS: &str = "+-*/()";
+
+fn ends_word(c: char) -> bool {
+    c.is_whitespace() || ",;\"'".contains(c) || OPERATORS.contains(c)
+}
+
+/// Splits one line of source into tokens, dropping the comment.
+pub fn tokenize(line: &str) -> Result<Vec<Token>, LexError> {
+    let chars: Vec<char> = line.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let column = i + 1;
+        let kind = if c == ';' {
+            break;
+        } else if c.is_whitespace() {
+            i += 1;
+            continue;
+        } else if c == '#'
+            && chars
+                .get(i + 1)
+                .map_or(false, |&c| c == '(' || c == '_' || c.is_alphabetic())
+        {
+            // `#SIZE` or `#(SIZE*2)`: the mark adds nothing to an expression
+            i += 1;
+            continue;
+        } else if c == ',' {
+            i += 1;
+            TokenKind::Comma
+        } else if OPERATORS.contains(c) {
+            i += 1;
+            TokenKind::Operator(c)
+        } else if c == '"' {
+            let (string, end) = quoted(&chars, i, '"')?;
+            i = end;
+            TokenKind::Str(string)
+        } else if c == '\'' {
+            let (string, end) = quoted(&chars, i, '\'')?;
+            let mut string = string.chars();
+            match (string.next(), string.next()) {
+                (Some(c), None) if (c as u32) <= 0xFFFF => {
+                    i = end;
+                    TokenKind::Number(c as i32)
+                }
+                _ => {
+                    return Err(LexError {
+                        column,
+                        message: "a character literal holds one character".to_string(),
+                    })
+                }
+            }
+        } else {
+            let start = i;
+            // the sign of `#-1` and `x-10` belongs to the number
+            if (c == '#' || c == 'x' || c == 'X')
+                && chars.get(i + 1) == Some(&'-')
+                && chars.get(i + 2).map_or(false, |c| c.is_ascii_hexdigit())
+            {
+                i += 2;
+            }
+            while i < chars.len() && (i == start || !ends_word(chars[i])) {
+                i += 1;
+            }
+            let text: String = chars[start..i].iter().collect();
+            match number(&text) {
+                Some(Ok(value)) => TokenKind::Number(value),
+                Some(Err(())) => {
+                    return Err(LexError {
+                        column,
+                        message: format!("invalid number `{}`", text),
+                    })
+                }
+                None => TokenKind::Word(text),
+            }
+        };
+        tokens.push(Token { kind, column });
+    }
+    Ok(tokens)
+}
+
+/// Reads the literal starting at the quote at `start`. Returns it with its escapes resolved,
+/// along with the index just past the closing quote.
+fn quoted(chars: &[char], start: usize, quote: char) -> Result<(String, usize), LexError> {
+    let mut string = String::new();
+    let mut i = start + 1;
+    while i < chars.len() {
+        match chars[i] {
+            c if c == quote => return Ok((string, i + 1)),
+            '\\' if i + 1 < chars.len() => {
+                i += 1;
+                string.push(match chars[i] {
+                    'n' => '\n',
+                    'r' => '\r',
+                    't' => '\t',
+                    'e' => '\x1b',
+                    '0' => '\0',
+                    c => c,
+                });
+            }
+            c => string.push(c),
+        }
+        i += 1;
+    }
+    Err(LexError {
+        column: start + 1,
+        message: if quote == '"' {
+            "unterminated string".to_string()
+        } else {
+            "unterminated character literal".to_string()
+        },
+    })
+}
+
+/// Parses `text` if it looks like a number. `None` means it is a word, labels like `x` or
+/// `xray` included.
+fn number(text: &str) -> Option<Result<i32, ()>> {
+    let (digits, radix) = if text.starts_with('#') {
+        (&text[1..], 10)
+    } else if (text.starts_with('x') || text.starts_with('X'))
+        && text.len() > 1
+        && text[1..]
+            .trim_start_matches('-')
+            .chars()
+            .all(|c| c.is_ascii_hexdigit())
+    {
+        (&text[1..], 16)
+    } else if text.starts_with(|c: char| c.is_ascii_digit()) {
+        (text, 10)
+    } else {
+        return None;
+    };
+    let (negative, digits) = if digits.starts_with('-') {
+        (true, &digits[1..])
+    } else {
+        (false, digits)
+    };
+    Some(
+        i32::from_str_radix(digits, radix)
+            .ok()
+            .filter(|&value| value <= 0xFFFF)
+            .map(|value| if negative { -value } else { value })
+            .ok_or(()),
+    )
+}
+
+#[test]
+fn test_tokenize() {
+    use TokenKind::*;
+    let kinds = |line| -> Vec<TokenKind> {
+        tokenize(line)
+            .unwrap()
+            .into_iter()
+            .map(|token| token.kind)
+            .collect()
+    };
+    assert_eq!(
+        kinds("LOOP ADD R1, R1, #-1 ; count down"),
+        vec![
+            Word("LOOP".into()),
+            Word("ADD".into()),
+            Word("R1".into()),
+            Comma,
+            Word("R1".into()),
+            Comma,
+            Number(-1)
+        ]
+    );
+    assert_eq!(
+        kinds(".FILL xFFFF"),
+        vec![Word(".FILL".into()), Number(0xFFFF)]
+    );
+    assert_eq!(
+        kinds("xray x-10 12"),
+        vec![Word("xray".into()), Number(-16), Number(12)]
+    );
+    assert_eq!(
+        kinds(r#".STRINGZ "a;b\"\n""#),
+        vec![Word(".STRINGZ".into()), Str("a;b\"\n".into())]
+    );
+    assert_eq!(
+        kinds("LABEL+2 x10*2 (1-'A')"),
+        vec![
+            Word("LABEL".into()),
+            Operator('+'),
+            Number(2),
+            Number(16),
+            Operator('*'),
+            Number(2),
+            Operator('('),
+            Number(1),
+            Operator('-'),
+            Number(65),
+            Operator(')')
+        ]
+    );
+    assert_eq!(kinds("'\\n' ';'"), vec![Number(10), Number(59)]);
+    assert_eq!(kinds("#SIZE"), vec![Word("SIZE".into())]);
+    assert_eq!(tokenize("  ADD").unwrap()[0].column, 3);
+    assert_eq!(tokenize(".STRINGZ \"open").unwrap_err().column, 10);
+    assert!(tokenize("#12a").is_err());
+    assert!(tokenize("'ab'").is_err());
+}
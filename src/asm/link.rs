@@ -0,0 +1,265 @@
+//! Joins relocatable objects into one program that `read_image` can load.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use super::object::{Object, RelocationKind};
+use super::Program;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LinkError {
+    pub message: String,
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for LinkError {}
+
+#[derive(Debug)]
+struct Module {
+    name: String,
+    object: Object,
+    origin: u16,
+}
+
+impl Module {
+    /// How far the module moved from where it was assembled.
+    fn delta(&self) -> u16 {
+        self.origin.wrapping_sub(self.object.origin)
+    }
+
+    fn end(&self) -> u32 {
+        u32::from(self.origin) + self.object.words.len() as u32
+    }
+}
+
+/// Places modules in memory and resolves the references between them. The program spans
+/// from the lowest module to the end of the highest, gaps filled with zeros.
+#[derive(Debug, Default)]
+pub struct Linker {
+    modules: Vec<Module>,
+}
+
+impl Linker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a module, to be placed at `origin` or, if `None`, where it was assembled. `name`
+    /// is used in errors.
+    pub fn add(&mut self, name: &str, object: Object, origin: Option<u16>) {
+        let origin = origin.unwrap_or(object.origin);
+        self.modules.push(Module {
+            name: name.to_string(),
+            object,
+            origin,
+        });
+    }
+
+    pub fn link(&self) -> Result<Program, LinkError> {
+        let error = |message: String| LinkError { message };
+        let mut order: Vec<&Module> = self.modules.iter().collect();
+        order.sort_by_key(|module| module.origin);
+        for (index, module) in order.iter().enumerate() {
+            if module.end() > 0x10000 {
+                return Err(error(format!(
+                    "{} runs past the end of memory at x{:04X}",
+                    module.name, module.origin
+                )));
+            }
+            if let Some(next) = order.get(index + 1) {
+                if module.end() > u32::from(next.origin) {
+                    return Err(error(format!(
+                        "{} at x{:04X} overlaps {} at x{:04X}",
+                        module.name, module.origin, next.name, next.origin
+                    )));
+                }
+            }
+        }
+
+        let mut globals: HashMap<&str, (u16, &str)> = HashMap::new();
+        for module in &self.modules {
+            for name in &module.object.globals {
+                let addr = match module
+                    .object
+                    .symbols
+                    .iter()
+                    .find(|(label, _)| label == name)
+                {
+                    Some((_, addr)) => addr.wrapping_add(module.delta()),
+                    None => {
+                        return Err(error(format!(
+                            "{}: `{}` is global but not a label",
+                            module.name, name
+                        )))
+                    }
+                };
+                if let Some((_, other)) = globals.insert(name, (addr, &module.name)) {
+                    return Err(error(format!(
+                        "`{}` is defined by both {} and {}",
+                        name, other, module.name
+                    )));
+                }
+            }
+        }
+
+        let start = order.first().map_or(0, |module| module.origin);
+        let end = order.last().map_or(0, |module| module.end());
+        let mut words = vec![0; (end - u32::from(start)) as usize];
+        let mut symbols = Vec::new();
+        for module in &self.modules {
+            let object = &module.object;
+            for name in &object.externals {
+                if !globals.contains_key(name.as_str()) {
+                    return Err(error(format!(
+                        "{}: undefined external `{}`",
+                        module.name, name
+                    )));
+                }
+            }
+            let base = usize::from(module.origin - start);
+            words[base..base + object.words.len()].copy_from_slice(&object.words);
+            for relocation in &object.relocations {
+                let offset = relocation.addr.wrapping_sub(object.origin);
+                if usize::from(offset) >= object.words.len() {
+                    return Err(error(format!(
+                        "{}: relocation at x{:04X} is outside the module",
+                        module.name, relocation.addr
+                    )));
+                }
+                let addr = module.origin + offset;
+                let word = &mut words[base + usize::from(offset)];
+                let symbol = match &relocation.symbol {
+                    Some(symbol) => symbol,
+                    None if relocation.kind == RelocationKind::Word => {
+                        *word = word.wrapping_add(module.delta());
+                        continue;
+                    }
+                    None => {
+                        return Err(error(format!(
+                            "{}: PC offset at x{:04X} refers to no symbol",
+                            module.name, relocation.addr
+                        )))
+                    }
+                };
+                let target = match globals.get(symbol.as_str()) {
+                    Some((target, _)) => i32::from(*target) + relocation.addend,
+                    None => {
+                        return Err(error(format!(
+                            "{}: undefined external `{}`",
+                            module.name, symbol
+                        )))
+                    }
+                };
+                let bits = match relocation.kind {
+                    RelocationKind::Word => {
+                        *word = target as u16;
+                        continue;
+                    }
+                    RelocationKind::PcOffset9 => 9,
+                    RelocationKind::PcOffset11 => 11,
+                };
+                let offset = target - (i32::from(addr) + 1);
+                let limit = 1 << (bits - 1);
+                if offset < -limit || offset >= limit {
+                    return Err(error(format!(
+                        "{}: `{}` is too far from x{:04X}: PC offset {} does not fit in {} bits",
+                        module.name, symbol, addr, offset, bits
+                    )));
+                }
+                let mask = (1 << bits) - 1;
+                *word = (*word & !mask) | (offset as u16 & mask);
+            }
+            for (name, addr) in &object.symbols {
+                symbols.push((name.clone(), addr.wrapping_add(module.delta())));
+            }
+        }
+        Ok(Program {
+            origin: start,
+            words,
+            symbols,
+            lines: Vec::new(),
+        })
+    }
+}
+
+#[test]
+fn test_link() {
+    use super::assemble_object;
+
+    let main = assemble_object(
+        "        .ORIG x3000
+        .EXTERNAL PRINT, COUNT
+        LEA R0, HELLO
+        JSR PRINT
+        LD R1, COUNT
+        ADD R0, R1, #0
+        JSR PRINT
+        HALT
+HELLO   .STRINGZ \"hi \"
+        .END",
+    )
+    .unwrap();
+    // assembled at x0000 but placed after main: its pointer has to move along
+    let library = assemble_object(
+        "        .ORIG x0000
+        .GLOBAL PRINT, COUNT
+PRINT   PUTS
+        LDI R0, POINTER
+        OUT
+        RET
+COUNT   .FILL DIGITS
+POINTER .FILL DIGITS
+DIGITS  .STRINGZ \"7\"
+        .END",
+    )
+    .unwrap();
+
+    let mut linker = Linker::new();
+    linker.add("main", main.clone(), None);
+    linker.add("library", library.clone(), Some(0x3020));
+    let program = linker.link().unwrap();
+    assert_eq!(program.origin, 0x3000);
+    assert_eq!(program.words.len(), 0x20 + library.words.len());
+    assert!(program.symbols.contains(&("PRINT".to_string(), 0x3020)));
+    assert!(program.symbols.contains(&("DIGITS".to_string(), 0x3026)));
+
+    let mut vm = crate::vm::VirtualMachine::with_memory(0x10000);
+    let console = crate::console::BufferConsole::new();
+    vm.set_console(Box::new(console.clone()));
+    vm.read_image(&program.image()).unwrap();
+    assert_eq!(vm.run(), crate::vm::StopReason::Halted);
+    assert_eq!(console.take_output(), b"hi 777HALTING\n");
+
+    let error = |modules: &[(&str, &Object, Option<u16>)]| {
+        let mut linker = Linker::new();
+        for (name, object, origin) in modules {
+            linker.add(name, (*object).clone(), *origin);
+        }
+        linker.link().unwrap_err().message
+    };
+    assert_eq!(
+        error(&[("main", &main, None)]),
+        "main: undefined external `PRINT`"
+    );
+    assert_eq!(
+        error(&[("main", &main, None), ("library", &library, Some(0x3004))]),
+        "main at x3000 overlaps library at x3004"
+    );
+    assert_eq!(
+        error(&[("main", &main, None), ("library", &library, Some(0x3400))]),
+        "main: `COUNT` is too far from x3002: PC offset 1025 does not fit in 9 bits"
+    );
+    assert_eq!(
+        error(&[
+            ("main", &main, None),
+            ("library", &library, Some(0x3020)),
+            ("copy", &library, Some(0x3100)),
+        ]),
+        "`PRINT` is defined by both library and copy"
+    );
+}
@@ -0,0 +1,1316 @@
+//! A two-pass LC-3 assembler. The source is first preprocessed, expanding `.INCLUDE` and
+//! macros. The first pass then splits it into statements and assigns every label an
+//! address, the second encodes the statements now that all labels are known.
+//!
+//! Besides the usual directives, `NAME .EQU expr` defines a constant, and `NAME .SET expr`
+//! a variable that can be set again: each use takes the value of the last `.SET` above it.
+//! Operands can be expressions such as `LABEL+2`, `'A'` or `x10*2`.
+//!
+//! Errors do not stop the assembler: a line in error is skipped, as far as possible without
+//! upsetting the lines after it, so that all the errors in a file are found at once.
+//!
+//! A program can also be split into modules, each assembled into an `Object`. A module
+//! exports labels with `.GLOBAL NAME, ...` and imports those of others with
+//! `.EXTERNAL NAME, ...`, then the `Linker` places the modules and joins them.
+
+mod diagnostics;
+mod expr;
+mod lexer;
+mod link;
+mod object;
+mod preprocess;
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::io;
+use std::path::Path;
+
+use crate::bits::{self, AluOperand, Instruction, TrapCode};
+use crate::symbols::SymbolTable;
+
+pub use self::diagnostics::{AsmError, Diagnostics};
+use self::expr::{Expr, Value};
+use self::lexer::{Token, TokenKind};
+pub use self::link::Linker;
+pub use self::object::{Object, Relocation, RelocationKind};
+use self::preprocess::{Line, Location};
+
+/// The output of the assembler: `words` to be loaded at `origin`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+    pub origin: u16,
+    pub words: Vec<u16>,
+    /// Labels and their addresses, in the order they were defined.
+    pub symbols: Vec<(String, u16)>,
+    /// The source, for the listing. Linked programs have none.
+    pub lines: Vec<SourceLine>,
+}
+
+/// A line of source, after macros are expanded, and where its words are.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLine {
+    pub text: String,
+    pub addr: u16,
+    /// In words, 0 for lines that assemble to nothing.
+    pub size: usize,
+}
+
+impl Program {
+    /// The program as an object file: the origin followed by the words, all big-endian.
+    /// This is what `VirtualMachine::read_image` expects.
+    pub fn image(&self) -> Vec<u8> {
+        std::iter::once(&self.origin)
+            .chain(&self.words)
+            .flat_map(|word| word.to_be_bytes().to_vec())
+            .collect()
+    }
+
+    pub fn symbol_table(&self) -> SymbolTable {
+        let mut table = SymbolTable::new();
+        for (name, addr) in &self.symbols {
+            table.insert(name, *addr);
+        }
+        table
+    }
+
+    /// Every line of source with the address and words it assembled to, in hex and binary,
+    /// then the symbol table. Words past the first of a line are on lines of their own.
+    pub fn listing(&self) -> String {
+        let mut listing = String::new();
+        for line in &self.lines {
+            let start = usize::from(line.addr.wrapping_sub(self.origin));
+            let words = self.words.get(start..start + line.size).unwrap_or(&[]);
+            let mut text = line.text.as_str();
+            if words.is_empty() {
+                let _ = writeln!(listing, "{:4} | {:4} | {:16} | {}", "", "", "", text);
+            }
+            let addresses = (0..).map(|index| line.addr.wrapping_add(index));
+            for (addr, word) in addresses.zip(words) {
+                let _ = writeln!(
+                    listing,
+                    "{:04X} | {:04X} | {:016b} | {}",
+                    addr, word, word, text
+                );
+                text = "";
+            }
+        }
+        // no trailing spaces where the text is empty
+        let mut listing: String = listing
+            .lines()
+            .map(|line| format!("{}\n", line.trim_end()))
+            .collect();
+        listing.push_str("\nSymbol table\n");
+        for (addr, name) in self.symbol_table().iter() {
+            let _ = writeln!(listing, "{:04X} | {}", addr, name);
+        }
+        listing
+    }
+
+    /// Writes the image to `path`, and beside it the symbol table in lc3as format with the
+    /// `.sym` extension and, if there is source, the listing with the `.lst` extension.
+    pub fn write_files<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        std::fs::write(path, self.image())?;
+        std::fs::write(path.with_extension("sym"), self.symbol_table().to_lc3as())?;
+        if !self.lines.is_empty() {
+            std::fs::write(path.with_extension("lst"), self.listing())?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Operand {
+    Register(u16),
+    Expr(Expr),
+    Str(String),
+}
+
+#[derive(Debug)]
+struct Statement {
+    location: Location,
+    /// Position of the line in the preprocessed source, to report errors in order.
+    index: usize,
+    /// Upper case, so that mnemonics are case insensitive.
+    mnemonic: String,
+    /// Column of the mnemonic.
+    column: Option<usize>,
+    operands: Vec<Operand>,
+    /// Column of each operand.
+    columns: Vec<Option<usize>>,
+    addr: u16,
+    /// In words.
+    size: u32,
+}
+
+const DIRECTIVES: &[&str] = &[
+    ".ORIG",
+    ".FILL",
+    ".BLKW",
+    ".STRINGZ",
+    ".END",
+    ".EQU",
+    ".SET",
+    ".INCLUDE",
+    ".MACRO",
+    ".ENDM",
+    ".GLOBAL",
+    ".EXTERNAL",
+];
+
+const MNEMONICS: &[&str] = &[
+    "ADD", "AND", "NOT", "JMP", "RET", "JSR", "JSRR", "LD", "LDI", "LDR", "LEA", "ST", "STI",
+    "STR", "TRAP", "RTI", "NOP", "GETC", "OUT", "PUTS", "IN", "PUTSP", "HALT",
+];
+
+fn is_mnemonic(word: &str) -> bool {
+    let word = word.to_ascii_uppercase();
+    MNEMONICS.contains(&&*word) || DIRECTIVES.contains(&&*word) || branch_mask(&word).is_some()
+}
+
+/// The nzp bits of a BR mnemonic. Plain BR branches always.
+fn branch_mask(mnemonic: &str) -> Option<u16> {
+    if !mnemonic.starts_with("BR") {
+        return None;
+    }
+    let mut mask = 0;
+    for c in mnemonic[2..].chars() {
+        let bit = match c {
+            'N' => 0b100,
+            'Z' => 0b010,
+            'P' => 0b001,
+            _ => return None,
+        };
+        if mask & bit != 0 {
+            return None;
+        }
+        mask |= bit;
+    }
+    Some(if mask == 0 { 0b111 } else { mask })
+}
+
+fn is_label(word: &str) -> bool {
+    word.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && word.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Whether `word` can be written as a label, which mnemonics and registers cannot.
+pub(crate) fn is_free_label(word: &str) -> bool {
+    is_label(word) && !is_mnemonic(word) && register(word).is_none()
+}
+
+fn register(word: &str) -> Option<u16> {
+    let bytes = word.as_bytes();
+    if bytes.len() == 2
+        && (bytes[0] == b'R' || bytes[0] == b'r')
+        && (b'0'..=b'7').contains(&bytes[1])
+    {
+        Some(u16::from(bytes[1] - b'0'))
+    } else {
+        None
+    }
+}
+
+/// Labels, the constants defined with `.EQU` and the labels of other modules.
+#[derive(Debug, Default)]
+struct Symbols {
+    labels: Vec<(String, u16)>,
+    addresses: HashMap<String, u16>,
+    constants: HashMap<String, Value>,
+    externals: Vec<String>,
+    /// Where each name, variables included, was first defined.
+    definitions: HashMap<String, Location>,
+}
+
+impl Symbols {
+    fn lookup(&self, name: &str) -> Option<Value> {
+        match self.addresses.get(name) {
+            Some(&addr) => Some(Value::address(addr)),
+            None => match self.constants.get(name) {
+                Some(&value) => Some(value),
+                None => self
+                    .externals
+                    .iter()
+                    .position(|external| external == name)
+                    .map(Value::external),
+            },
+        }
+    }
+
+    /// Records that `name` is defined at `location`, unless it already is.
+    fn define(
+        &mut self,
+        name: &str,
+        location: &Location,
+        column: Option<usize>,
+    ) -> Result<(), AsmError> {
+        match self.definitions.get(name) {
+            Some(first) => {
+                let place = match (&first.file, &location.file) {
+                    (Some(file), Some(here)) if file != here => format!("{}:{}", file, first.line),
+                    _ => format!("line {}", first.line),
+                };
+                Err(AsmError::at(
+                    location,
+                    column,
+                    format!("duplicate label `{}`, first defined at {}", name, place),
+                ))
+            }
+            None => {
+                self.definitions.insert(name.to_string(), location.clone());
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Assembles `source` into a program. Includes are looked up in the working directory.
+pub fn assemble(source: &str) -> Result<Program, Diagnostics> {
+    assemble_source(source, None, false).map(|(object, lines)| object.into_program(lines))
+}
+
+/// Assembles the file at `path`. Includes are looked up relative to the file.
+pub fn assemble_file<P: AsRef<Path>>(path: P) -> Result<Program, Diagnostics> {
+    let path = path.as_ref();
+    assemble_source(&read_source(path)?, Some(path), false)
+        .map(|(object, lines)| object.into_program(lines))
+}
+
+/// Assembles `source` into an object, to be linked with the modules it imports from.
+pub fn assemble_object(source: &str) -> Result<Object, Diagnostics> {
+    assemble_source(source, None, true).map(|(object, _)| object)
+}
+
+/// Assembles the file at `path` into an object.
+pub fn assemble_object_file<P: AsRef<Path>>(path: P) -> Result<Object, Diagnostics> {
+    let path = path.as_ref();
+    assemble_source(&read_source(path)?, Some(path), true).map(|(object, _)| object)
+}
+
+fn read_source(path: &Path) -> Result<String, AsmError> {
+    std::fs::read_to_string(path).map_err(|e| AsmError {
+        file: Some(path.to_string_lossy().into_owned()),
+        line: 0,
+        column: None,
+        message: e.to_string(),
+        source: None,
+    })
+}
+
+/// Preprocesses `source` and runs both passes over it. Only objects can use `.EXTERNAL`.
+fn assemble_source(
+    source: &str,
+    path: Option<&Path>,
+    relocatable: bool,
+) -> Result<(Object, Vec<SourceLine>), Diagnostics> {
+    let (lines, mut errors) = preprocess::preprocess(source, path);
+    let mut pass = FirstPass {
+        relocatable,
+        ..FirstPass::default()
+    };
+    for (index, line) in lines.iter().enumerate() {
+        if let Err(error) = pass.line(index, line) {
+            pass.errors.push((index, error));
+        }
+        if pass.ended {
+            break;
+        }
+    }
+    errors.append(&mut pass.errors);
+    for (name, index, location, column) in &pass.globals {
+        let message = match pass.symbols.lookup(name) {
+            Some(Value {
+                address: true,
+                external: None,
+                ..
+            }) => continue,
+            Some(_) => format!("`{}` cannot be global, only labels can", name),
+            None => format!("undefined label `{}`", name),
+        };
+        errors.push((*index, AsmError::at(location, *column, message)));
+    }
+    if pass.origin.is_none() {
+        let error = match lines.last() {
+            Some(line) => AsmError::at(&line.location, None, "missing .ORIG".to_string()),
+            None => AsmError::new(1, "missing .ORIG".to_string()),
+        };
+        errors.push((lines.len(), error));
+    }
+
+    let mut words = Vec::new();
+    let mut relocations = Vec::new();
+    for statement in &pass.statements {
+        if let Err(error) = encode(statement, &pass.symbols, &mut words, &mut relocations) {
+            errors.push((statement.index, error));
+        }
+    }
+    if !errors.is_empty() {
+        // stable, so that errors on the same line stay in the order they were found
+        errors.sort_by_key(|(index, _)| *index);
+        return Err(Diagnostics {
+            errors: errors.into_iter().map(|(_, error)| error).collect(),
+        });
+    }
+    let mut source_lines: Vec<SourceLine> = lines
+        .iter()
+        .map(|line| SourceLine {
+            text: line.text.trim_end().to_string(),
+            addr: 0,
+            size: 0,
+        })
+        .collect();
+    for statement in &pass.statements {
+        let line = &mut source_lines[statement.index];
+        line.addr = statement.addr;
+        line.size = statement.size as usize;
+    }
+    let object = Object {
+        origin: pass.origin.unwrap_or_default(),
+        words,
+        symbols: pass.symbols.labels,
+        globals: pass.globals.into_iter().map(|(name, ..)| name).collect(),
+        externals: pass.symbols.externals,
+        relocations,
+    };
+    Ok((object, source_lines))
+}
+
+/// Parses the operands of a statement, which are separated by commas, each with its
+/// column. Errors come with their column too.
+fn operands(tokens: &[Token]) -> Result<Vec<(Operand, usize)>, (usize, String)> {
+    let mut operands = Vec::new();
+    let mut i = 0;
+    while i < tokens.len() {
+        let column = tokens[i].column;
+        match &tokens[i].kind {
+            TokenKind::Word(word) if register(word).is_some() => {
+                operands.push((
+                    Operand::Register(register(word).unwrap_or_default()),
+                    column,
+                ));
+                i += 1;
+            }
+            TokenKind::Str(string) => {
+                operands.push((Operand::Str(string.clone()), column));
+                i += 1;
+            }
+            TokenKind::Comma => return Err((column, "missing operand".to_string())),
+            _ => {
+                let (expr, used) = expr::parse(&tokens[i..])?;
+                operands.push((Operand::Expr(expr), column));
+                i += used;
+            }
+        }
+        if let Some(Token {
+            kind: TokenKind::Comma,
+            column,
+        }) = tokens.get(i)
+        {
+            i += 1;
+            if i == tokens.len() {
+                return Err((*column, "missing operand".to_string()));
+            }
+        }
+    }
+    Ok(operands)
+}
+
+/// What the first pass has found so far.
+#[derive(Default)]
+struct FirstPass {
+    origin: Option<u16>,
+    statements: Vec<Statement>,
+    symbols: Symbols,
+    variables: HashMap<String, i32>,
+    // u32, so that running past the end of memory can be detected
+    location: u32,
+    /// Set at `.END`, after which lines are ignored.
+    ended: bool,
+    /// Whether the module is assembled as an object, which allows `.EXTERNAL`.
+    relocatable: bool,
+    /// The names declared `.GLOBAL`, to be checked once all labels are known, with the line
+    /// index and where they were declared.
+    globals: Vec<(String, usize, Location, Option<usize>)>,
+    /// Errors that did not stop a line from being assembled, with the line index.
+    errors: Vec<(usize, AsmError)>,
+}
+
+impl FirstPass {
+    /// Parses the line at `index`, and assigns addresses to its statement and label.
+    fn line(&mut self, index: usize, line: &Line) -> Result<(), AsmError> {
+        let location = &line.location;
+        let column = |column: usize| location.column(&line.text, column);
+        let error =
+            |column: Option<usize>, message: String| AsmError::at(location, column, message);
+        let tokens = lexer::tokenize(&line.text).map_err(|e| error(column(e.column), e.message))?;
+        let mut tokens = tokens.as_slice();
+        let mut label = None;
+        if let Some(token) = tokens.first() {
+            if let TokenKind::Word(word) = &token.kind {
+                if !is_mnemonic(word) {
+                    let name = word.trim_end_matches(':').to_string();
+                    if !is_label(&name) {
+                        return Err(error(
+                            column(token.column),
+                            format!("invalid label `{}`", word),
+                        ));
+                    }
+                    label = Some((name, column(token.column)));
+                    tokens = &tokens[1..];
+                }
+            }
+        }
+        let parsed = tokens
+            .first()
+            .map(|token| match &token.kind {
+                TokenKind::Word(word) if is_mnemonic(word) => {
+                    Ok((word.to_ascii_uppercase(), column(token.column)))
+                }
+                TokenKind::Word(word) => Err(error(
+                    column(token.column),
+                    format!("unknown opcode `{}`", word),
+                )),
+                _ => Err(error(
+                    column(token.column),
+                    "expected an opcode".to_string(),
+                )),
+            })
+            .transpose()
+            .and_then(|mnemonic| {
+                let operands = operands(tokens.get(1..).unwrap_or(&[]))
+                    .map_err(|(at, message)| error(column(at), message))?;
+                Ok((mnemonic, operands))
+            });
+        let (mnemonic, operands) = match parsed {
+            Ok(parsed) => parsed,
+            Err(failure) => {
+                // taken to be an instruction, so that its label and the addresses after it
+                // are still right
+                let directive = match tokens.first().map(|token| &token.kind) {
+                    Some(TokenKind::Word(word)) => word.starts_with('.'),
+                    _ => false,
+                };
+                if !directive && self.origin.is_some() && self.location <= 0xFFFF {
+                    if let Some((name, at)) = label {
+                        if let Err(error) = self.define_label(name, location, at) {
+                            self.errors.push((index, error));
+                        }
+                    }
+                    self.location += 1;
+                }
+                return Err(failure);
+            }
+        };
+        let mnemonic_column = mnemonic.as_ref().and_then(|(_, at)| *at);
+        let mnemonic = mnemonic.map(|(mnemonic, _)| mnemonic);
+        let (mut operands, columns): (Vec<Operand>, Vec<Option<usize>>) = operands
+            .into_iter()
+            .map(|(operand, at)| (operand, column(at)))
+            .unzip();
+        // variables take the value they have at this point
+        for operand in &mut operands {
+            if let Operand::Expr(expr) = operand {
+                expr.substitute(&self.variables);
+            }
+        }
+        // evaluated right away, so only symbols defined above can be used
+        let constant = |symbols: &Symbols, directive: &str| match operands.as_slice() {
+            [Operand::Expr(expr)] => expr
+                .eval(&|name| symbols.lookup(name))
+                .map_err(|message| error(columns[0], message)),
+            _ => Err(error(
+                mnemonic_column,
+                format!("{} expects an expression", directive),
+            )),
+        };
+
+        match mnemonic.as_ref().map(String::as_str) {
+            Some(directive @ ".EQU") | Some(directive @ ".SET") => {
+                let (name, at) = match label {
+                    Some(label) => label,
+                    None => {
+                        return Err(error(
+                            mnemonic_column,
+                            format!("{} needs a name", directive),
+                        ))
+                    }
+                };
+                let value = match constant(&self.symbols, directive) {
+                    Ok(Value { address: true, .. }) if directive == ".SET" => Err(error(
+                        columns[0],
+                        ".SET takes a number, not an address".to_string(),
+                    )),
+                    value => value,
+                };
+                if directive == ".EQU" || !self.variables.contains_key(&name) {
+                    self.symbols.define(&name, location, at)?;
+                }
+                // a name that failed still gets a value, not to report every use as undefined
+                let result = value.as_ref().map(|_| ()).map_err(Clone::clone);
+                let value = value.unwrap_or_else(|_| Value::number(0));
+                if directive == ".EQU" {
+                    self.symbols.constants.insert(name, value);
+                } else {
+                    self.variables.insert(name, value.value);
+                }
+                return result;
+            }
+            Some(directive @ ".GLOBAL") | Some(directive @ ".EXTERNAL") => {
+                if let Some((_, at)) = label {
+                    return Err(error(at, format!("{} cannot be labelled", directive)));
+                }
+                if directive == ".EXTERNAL" && !self.relocatable {
+                    return Err(error(
+                        mnemonic_column,
+                        ".EXTERNAL is only for modules assembled as objects".to_string(),
+                    ));
+                }
+                if operands.is_empty() {
+                    return Err(error(
+                        mnemonic_column,
+                        format!("{} expects label names", directive),
+                    ));
+                }
+                for (operand, &at) in operands.iter().zip(&columns) {
+                    let name = match operand {
+                        Operand::Expr(Expr::Symbol(name)) => name,
+                        _ => return Err(error(at, format!("{} expects label names", directive))),
+                    };
+                    if directive == ".GLOBAL" {
+                        if self.globals.iter().all(|(global, ..)| global != name) {
+                            self.globals
+                                .push((name.clone(), index, location.clone(), at));
+                        }
+                    } else {
+                        self.symbols.define(name, location, at)?;
+                        self.symbols.externals.push(name.clone());
+                    }
+                }
+                return Ok(());
+            }
+            Some(".ORIG") => {
+                if self.origin.is_some() {
+                    return Err(error(mnemonic_column, "duplicate .ORIG".to_string()));
+                }
+                let origin = match constant(&self.symbols, ".ORIG") {
+                    Ok(Value {
+                        value,
+                        external: None,
+                        ..
+                    }) if (0..=0xFFFF).contains(&value) => Ok(value as u16),
+                    Ok(_) => Err(error(columns[0], ".ORIG expects an address".to_string())),
+                    Err(e) => Err(e),
+                };
+                // on error, carrying on from the usual x3000 still checks the rest of the file
+                self.origin = Some(*origin.as_ref().unwrap_or(&0x3000));
+                self.location = u32::from(self.origin.unwrap_or_default());
+                origin?;
+                if let Some((_, at)) = label {
+                    return Err(error(at, ".ORIG cannot be labelled".to_string()));
+                }
+                return Ok(());
+            }
+            _ => {}
+        }
+        if (label.is_some() || mnemonic.is_some()) && self.origin.is_none() {
+            let at = label.as_ref().map_or(mnemonic_column, |(_, at)| *at);
+            self.errors
+                .push((index, error(at, "expected .ORIG first".to_string())));
+            // reported once, x3000 is assumed from there on
+            self.origin = Some(0x3000);
+            self.location = 0x3000;
+        }
+        // memory may be filled up to xFFFF, but there is no address left for a label after it
+        if self.location > 0xFFFF {
+            if let Some((_, at)) = label {
+                return Err(error(at, "label past the end of memory".to_string()));
+            }
+        }
+        if let Some((name, at)) = label {
+            if let Err(error) = self.define_label(name, location, at) {
+                self.errors.push((index, error));
+            }
+        }
+        let mnemonic = match mnemonic {
+            Some(mnemonic) => mnemonic,
+            None => return Ok(()),
+        };
+        if mnemonic == ".END" {
+            self.ended = true;
+            return Ok(());
+        }
+        let size = match (mnemonic.as_str(), operands.as_slice()) {
+            (".BLKW", _) => match constant(&self.symbols, ".BLKW")? {
+                Value {
+                    value,
+                    address: false,
+                    ..
+                } if value >= 0 => value as u32,
+                _ => return Err(error(columns[0], ".BLKW expects a word count".to_string())),
+            },
+            (".STRINGZ", [Operand::Str(string)]) => string.chars().count() as u32 + 1,
+            (".STRINGZ", _) => {
+                return Err(error(
+                    mnemonic_column,
+                    ".STRINGZ expects a string".to_string(),
+                ))
+            }
+            _ => 1,
+        };
+        self.statements.push(Statement {
+            location: location.clone(),
+            index,
+            mnemonic,
+            column: mnemonic_column,
+            operands,
+            columns,
+            addr: self.location as u16,
+            size,
+        });
+        self.location += size;
+        if self.location > 0x10000 {
+            self.ended = true;
+            return Err(error(
+                mnemonic_column,
+                "program runs past the end of memory".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn define_label(
+        &mut self,
+        name: String,
+        location: &Location,
+        column: Option<usize>,
+    ) -> Result<(), AsmError> {
+        self.symbols.define(&name, location, column)?;
+        let addr = self.location as u16;
+        self.symbols.addresses.insert(name.clone(), addr);
+        self.symbols.labels.push((name, addr));
+        Ok(())
+    }
+}
+
+/// Encodes `statement` at the end of `words`, noting in `relocations` the words that depend
+/// on where the module is placed.
+fn encode(
+    statement: &Statement,
+    symbols: &Symbols,
+    words: &mut Vec<u16>,
+    relocations: &mut Vec<Relocation>,
+) -> Result<(), AsmError> {
+    let mnemonic = statement.mnemonic.as_str();
+    let operands = &statement.operands;
+    let error =
+        |column: Option<usize>, message: String| AsmError::at(&statement.location, column, message);
+    let at = |index: usize| statement.columns[index];
+    let expect = |count: usize| {
+        if operands.len() == count {
+            Ok(())
+        } else {
+            Err(error(
+                statement.column,
+                format!(
+                    "{} expects {} operand{}, found {}",
+                    mnemonic,
+                    count,
+                    if count == 1 { "" } else { "s" },
+                    operands.len()
+                ),
+            ))
+        }
+    };
+    let reg = |index: usize| match operands[index] {
+        Operand::Register(r) => Ok(r),
+        _ => Err(error(
+            at(index),
+            format!("operand {} of {} must be a register", index + 1, mnemonic),
+        )),
+    };
+    let value = |index: usize| match &operands[index] {
+        Operand::Expr(expr) => expr
+            .eval(&|name| symbols.lookup(name))
+            .map_err(|message| error(at(index), message)),
+        _ => Err(error(
+            at(index),
+            format!(
+                "operand {} of {} must be a number or a label",
+                index + 1,
+                mnemonic
+            ),
+        )),
+    };
+    let signed = |index: usize, value: i32, bits: u32, what: String| {
+        let limit = 1 << (bits - 1);
+        if value >= -limit && value < limit {
+            Ok(value as i16)
+        } else {
+            Err(error(
+                at(index),
+                format!(
+                    "{} does not fit in {} bits, which hold {} to {}",
+                    what,
+                    bits,
+                    -limit,
+                    limit - 1
+                ),
+            ))
+        }
+    };
+    let imm = |index: usize, bits: u32| match value(index)? {
+        Value {
+            value,
+            address: false,
+            ..
+        } => signed(index, value, bits, format!("immediate {}", value)),
+        _ => Err(error(
+            at(index),
+            format!(
+                "operand {} of {} must be a number, not an address",
+                index + 1,
+                mnemonic
+            ),
+        )),
+    };
+    // addresses are turned into offsets from the incremented PC, numbers are offsets already
+    let mut pc_offset = |index: usize, bits: u32| {
+        let target = value(index)?;
+        if let Some(external) = target.external {
+            // the offset is left to the linker
+            relocations.push(Relocation {
+                addr: statement.addr,
+                kind: if bits == 9 {
+                    RelocationKind::PcOffset9
+                } else {
+                    RelocationKind::PcOffset11
+                },
+                symbol: Some(symbols.externals[external].clone()),
+                addend: target.value,
+            });
+            return Ok(0);
+        }
+        if !target.address {
+            return signed(
+                index,
+                target.value,
+                bits,
+                format!("PC offset {}", target.value),
+            );
+        }
+        let offset = target.value - (i32::from(statement.addr) + 1);
+        let what = match &operands[index] {
+            Operand::Expr(Expr::Symbol(name)) => {
+                format!("`{}` is too far away: PC offset {}", name, offset)
+            }
+            _ => format!("PC offset {}", offset),
+        };
+        signed(index, offset, bits, what)
+    };
+    let trap = |code: TrapCode| Instruction::Trap { vector: code as u8 };
+
+    let instruction = match mnemonic {
+        "ADD" | "AND" => {
+            expect(3)?;
+            let (dr, sr1) = (reg(0)?, reg(1)?);
+            let operand = match operands[2] {
+                Operand::Register(r) => AluOperand::Register(r),
+                _ => AluOperand::Immediate(imm(2, 5)?),
+            };
+            if mnemonic == "ADD" {
+                Instruction::Add { dr, sr1, operand }
+            } else {
+                Instruction::And { dr, sr1, operand }
+            }
+        }
+        "NOT" => {
+            expect(2)?;
+            Instruction::Not {
+                dr: reg(0)?,
+                sr: reg(1)?,
+            }
+        }
+        "JMP" => {
+            expect(1)?;
+            Instruction::Jmp { base: reg(0)? }
+        }
+        "RET" => {
+            expect(0)?;
+            Instruction::Jmp { base: 7 }
+        }
+        "JSR" => {
+            expect(1)?;
+            Instruction::Jsr {
+                offset: pc_offset(0, 11)?,
+            }
+        }
+        "JSRR" => {
+            expect(1)?;
+            Instruction::Jsrr { base: reg(0)? }
+        }
+        "LD" | "LDI" | "LEA" | "ST" | "STI" => {
+            expect(2)?;
+            let (r, offset) = (reg(0)?, pc_offset(1, 9)?);
+            match mnemonic {
+                "LD" => Instruction::Ld { dr: r, offset },
+                "LDI" => Instruction::Ldi { dr: r, offset },
+                "LEA" => Instruction::Lea { dr: r, offset },
+                "ST" => Instruction::St { sr: r, offset },
+                _ => Instruction::Sti { sr: r, offset },
+            }
+        }
+        "LDR" | "STR" => {
+            expect(3)?;
+            let (r, base, offset) = (reg(0)?, reg(1)?, imm(2, 6)?);
+            if mnemonic == "LDR" {
+                Instruction::Ldr {
+                    dr: r,
+                    base,
+                    offset,
+                }
+            } else {
+                Instruction::Str {
+                    sr: r,
+                    base,
+                    offset,
+                }
+            }
+        }
+        "TRAP" => {
+            expect(1)?;
+            match value(0)? {
+                Value {
+                    value: vector,
+                    external: None,
+                    ..
+                } if (0..=0xFF).contains(&vector) => Instruction::Trap {
+                    vector: vector as u8,
+                },
+                _ => {
+                    return Err(error(
+                        at(0),
+                        "TRAP expects a vector from x00 to xFF".to_string(),
+                    ))
+                }
+            }
+        }
+        "RTI" => {
+            expect(0)?;
+            Instruction::Rti
+        }
+        "NOP" => {
+            expect(0)?;
+            Instruction::Br { nzp: 0, offset: 0 }
+        }
+        "GETC" | "OUT" | "PUTS" | "IN" | "PUTSP" | "HALT" => {
+            expect(0)?;
+            trap(match mnemonic {
+                "GETC" => TrapCode::GetC,
+                "OUT" => TrapCode::Out,
+                "PUTS" => TrapCode::Puts,
+                "IN" => TrapCode::In,
+                "PUTSP" => TrapCode::PutSp,
+                _ => TrapCode::Halt,
+            })
+        }
+        ".FILL" => {
+            expect(1)?;
+            let fill = value(0)?;
+            if fill.address {
+                let symbol = fill.external.map(|index| symbols.externals[index].clone());
+                relocations.push(Relocation {
+                    addr: statement.addr,
+                    kind: RelocationKind::Word,
+                    addend: if symbol.is_some() { fill.value } else { 0 },
+                    symbol,
+                });
+            }
+            words.push(match fill {
+                // filled in by the linker
+                Value {
+                    external: Some(_), ..
+                } => 0,
+                Value { value, .. } if (-0x8000..=0xFFFF).contains(&value) => value as u16,
+                Value { value, .. } => {
+                    return Err(error(at(0), format!("{} does not fit in a word", value)))
+                }
+            });
+            return Ok(());
+        }
+        ".BLKW" => {
+            words.resize(words.len() + statement.size as usize, 0);
+            return Ok(());
+        }
+        ".STRINGZ" => {
+            if let Operand::Str(string) = &operands[0] {
+                for c in string.chars() {
+                    if c as u32 > 0xFFFF {
+                        return Err(error(
+                            at(0),
+                            format!("character {:?} does not fit in a word", c),
+                        ));
+                    }
+                    words.push(c as u16);
+                }
+            }
+            words.push(0);
+            return Ok(());
+        }
+        _ => match branch_mask(mnemonic) {
+            Some(nzp) => {
+                expect(1)?;
+                Instruction::Br {
+                    nzp,
+                    offset: pc_offset(0, 9)?,
+                }
+            }
+            None => {
+                return Err(error(
+                    statement.column,
+                    format!("unknown opcode `{}`", mnemonic),
+                ))
+            }
+        },
+    };
+    words.push(bits::encode(&instruction));
+    Ok(())
+}
+
+#[cfg(test)]
+fn assemble_words(source: &str) -> Vec<u16> {
+    assemble(source).unwrap().words
+}
+
+#[test]
+fn test_hello_world() {
+    let program = assemble(include_str!("../../res/hello_world.asm")).unwrap();
+    assert_eq!(program.origin, 0x3000);
+    let mut expected = vec![0xE002, 0xF022, 0xF025];
+    expected.extend(b"Hello World!".iter().map(|&c| u16::from(c)));
+    expected.push(0);
+    assert_eq!(program.words, expected);
+    assert_eq!(program.symbols, vec![("HELLO_STR".to_string(), 0x3003)]);
+    assert_eq!(&program.image()[..4], &[0x30, 0x00, 0xE0, 0x02]);
+
+    let mut vm = crate::vm::VirtualMachine::with_memory(0x10000);
+    let console = crate::console::BufferConsole::new();
+    vm.set_console(Box::new(console.clone()));
+    vm.read_image(&program.image()).unwrap();
+    assert_eq!(vm.run(), crate::vm::StopReason::Halted);
+    assert_eq!(console.take_output(), b"Hello World!HALTING\n");
+}
+
+#[test]
+fn test_listing() {
+    let program = assemble(
+        "; count down
+.MACRO CLEAR reg
+        AND \\reg, \\reg, #0
+.ENDM
+        .ORIG x3000
+START   CLEAR R1
+        ADD R1, R1, #-1   ; minus one
+        BRp START
+DATA    .STRINGZ \"ab\"
+        .END",
+    )
+    .unwrap();
+    assert_eq!(
+        program.listing(),
+        "     |      |                  | ; count down
+     |      |                  |         .ORIG x3000
+     |      |                  | START
+3000 | 5260 | 0101001001100000 |         AND R1, R1, #0
+3001 | 127F | 0001001001111111 |         ADD R1, R1, #-1   ; minus one
+3002 | 03FD | 0000001111111101 |         BRp START
+3003 | 0061 | 0000000001100001 | DATA    .STRINGZ \"ab\"
+3004 | 0062 | 0000000001100010 |
+3005 | 0000 | 0000000000000000 |
+     |      |                  |         .END
+
+Symbol table
+3000 | START
+3003 | DATA
+"
+    );
+}
+
+#[test]
+fn test_opcodes() {
+    let words = assemble_words(
+        "        .ORIG x3000
+        ADD R1, R2, R3
+        add r1, r2, #-16
+        AND R7, R0, #15
+        NOT R4, R5
+START   BRnzp START
+        BRz NEXT
+        BR #-1
+NEXT    JMP R2
+        RET
+        JSR START
+        JSRR R3
+        LD R0, DATA
+        LDI R1, DATA
+        LDR R2, R6, #-32
+        LEA R3, DATA
+        ST R4, DATA
+        STI R5, DATA
+        STR R6, R7, #31
+        TRAP x25
+        RTI
+        NOP
+        GETC
+        OUT
+        PUTS
+        IN
+        PUTSP
+        HALT
+DATA    .FILL xBEEF
+        .FILL DATA
+        .FILL #-1
+        .BLKW 2
+        .STRINGZ \"hi\"
+        .END
+        ADD R0, R0, R0",
+    );
+    assert_eq!(
+        words,
+        vec![
+            0x1283, 0x12B0, 0x5E2F, 0x997F, 0x0FFF, 0x0401, 0x0FFF, 0xC080, 0xC1C0, 0x4FFA, 0x40C0,
+            0x200F, 0xA20E, 0x65A0, 0xE60C, 0x380B, 0xBA0A, 0x7DDF, 0xF025, 0x8000, 0x0000, 0xF020,
+            0xF021, 0xF022, 0xF023, 0xF024, 0xF025, 0xBEEF, 0x301B, 0xFFFF, 0, 0, 0x68, 0x69, 0,
+        ]
+    );
+}
+
+#[test]
+fn test_errors() {
+    let error = |source: &str| {
+        let mut errors = assemble(source).unwrap_err().errors;
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        errors.remove(0)
+    };
+    assert_eq!(error("ADD R0, R0, #1").message, "expected .ORIG first");
+    assert_eq!(
+        error(".ORIG x3000\nBR NOWHERE"),
+        AsmError {
+            file: None,
+            line: 2,
+            column: Some(4),
+            message: "undefined label `NOWHERE`".to_string(),
+            source: Some("BR NOWHERE".to_string()),
+        }
+    );
+    assert_eq!(
+        error(".ORIG x3000\nADD R0, R0, #16").message,
+        "immediate 16 does not fit in 5 bits, which hold -16 to 15"
+    );
+    assert_eq!(
+        error(".ORIG x3000\n.FILL (-x8000*x8000*2)/-1").message,
+        "`/` overflows"
+    );
+    assert_eq!(
+        error(".ORIG x3000\nA ADD R0, R0, R0\nA NOP").message,
+        "duplicate label `A`, first defined at line 2"
+    );
+    assert_eq!(
+        error(".ORIG x3000\nBR FAR\n.BLKW 256\nFAR NOP").message,
+        "`FAR` is too far away: PC offset 256 does not fit in 9 bits, which hold -256 to 255"
+    );
+    assert_eq!(
+        error(".ORIG x3000\nJSR #1024").message,
+        "PC offset 1024 does not fit in 11 bits, which hold -1024 to 1023"
+    );
+    assert_eq!(error(".ORIG x3000\nADD R0, R0").column, Some(1));
+    assert_eq!(error(".ORIG x3000\nFOO R0").line, 2);
+    assert_eq!(
+        error(".ORIG xFFFF\nNOP\n  NOP"),
+        AsmError {
+            file: None,
+            line: 3,
+            column: Some(3),
+            message: "program runs past the end of memory".to_string(),
+            source: Some("  NOP".to_string()),
+        }
+    );
+    assert_eq!(
+        error(".ORIG xFFFE\n.BLKW 2\nEND\n.END").message,
+        "label past the end of memory"
+    );
+
+    assert_eq!(
+        error(".ORIG x3000\n.EXTERNAL PRINT").message,
+        ".EXTERNAL is only for modules assembled as objects"
+    );
+    let errors = assemble_object(
+        ".ORIG x3000\nSIZE .EQU 2\n.GLOBAL SIZE, NONE\n.EXTERNAL EXT\nTRAP EXT\nADD R0, R0, EXT",
+    )
+    .unwrap_err()
+    .errors;
+    let messages: Vec<&str> = errors.iter().map(|error| error.message.as_str()).collect();
+    assert_eq!(
+        messages,
+        vec![
+            "`SIZE` cannot be global, only labels can",
+            "undefined label `NONE`",
+            "TRAP expects a vector from x00 to xFF",
+            "operand 3 of ADD must be a number, not an address",
+        ]
+    );
+}
+
+#[test]
+fn test_end_of_memory() {
+    let program = assemble(".ORIG xFFFD\n.FILL 1\n.STRINGZ \"a\"\n.BLKW 0\n.END").unwrap();
+    assert_eq!(program.words, vec![1, 0x61, 0]);
+    assert!(program
+        .listing()
+        .contains("\nFFFE | 0061 | 0000000001100001 | .STRINGZ \"a\"\nFFFF | 0000 |"));
+    let program = assemble(".ORIG xFFFF\nNOP\n.END").unwrap();
+    assert_eq!(program.origin, 0xFFFF);
+    assert_eq!(program.words, vec![0x0000]);
+}
+
+#[test]
+fn test_error_recovery() {
+    let errors = assemble(
+        "        .ORIG x3000
+LOOP    ADDD R0, R0, #1
+        ADD R0, R0, #99
+        BRz DONE
+        LD R1, MISSING
+        .STRINGZ \"open
+DONE    HALT
+LOOP    .FILL 1,
+        .END",
+    )
+    .unwrap_err()
+    .errors;
+    let errors: Vec<(usize, Option<usize>, &str)> = errors
+        .iter()
+        .map(|error| (error.line, error.column, error.message.as_str()))
+        .collect();
+    // LOOP and DONE are still defined, at the right addresses
+    assert_eq!(
+        errors,
+        vec![
+            (2, Some(9), "unknown opcode `ADDD`"),
+            (
+                3,
+                Some(21),
+                "immediate 99 does not fit in 5 bits, which hold -16 to 15"
+            ),
+            (5, Some(16), "undefined label `MISSING`"),
+            (6, Some(18), "unterminated string"),
+            (8, Some(16), "missing operand"),
+        ]
+    );
+
+    let diagnostics = assemble(".ORIG x3000\nAND R1, R2, x20\nNOP\n\tLEA R9, #0").unwrap_err();
+    assert_eq!(
+        diagnostics.to_string(),
+        "error: line 2:13: immediate 32 does not fit in 5 bits, which hold -16 to 15
+    AND R1, R2, x20
+                ^
+
+error: line 4:6: operand 1 of LEA must be a register
+    \tLEA R9, #0
+    \t    ^
+"
+    );
+    assert_eq!(
+        diagnostics.to_json(),
+        "[{\"file\":null,\"line\":2,\"column\":13,\"message\":\"immediate 32 does not fit in 5 bits, which hold -16 to 15\"},\
+         {\"file\":null,\"line\":4,\"column\":6,\"message\":\"operand 1 of LEA must be a register\"}]"
+    );
+}
+
+#[test]
+fn test_constants_and_macros() {
+    let program = assemble(
+        "SIZE    .EQU 3
+COUNT   .SET 1
+        .ORIG x3000
+.MACRO CLEAR reg
+        AND \\reg, \\reg, #0
+.ENDM
+START   CLEAR R0
+        ADD R0, R0, #SIZE*2-1
+        ADD R1, R1, #COUNT
+COUNT   .SET COUNT+1
+        ADD R1, R1, #COUNT
+        LD R2, TABLE+SIZE
+        LEA R3, END-1
+        .FILL 'A'
+        .FILL x10*2
+        .FILL END-START
+TABLE   .BLKW SIZE+1
+END     .FILL TABLE
+        .END",
+    )
+    .unwrap();
+    assert_eq!(
+        program.words,
+        vec![
+            0x5020, 0x1025, 0x1261, 0x1262, 0x2407, 0xE606, 0x41, 0x20, 0x000D, 0, 0, 0, 0, 0x3009,
+        ]
+    );
+    assert_eq!(
+        program.symbols,
+        vec![
+            ("START".to_string(), 0x3000),
+            ("TABLE".to_string(), 0x3009),
+            ("END".to_string(), 0x300D),
+        ]
+    );
+
+    let error = |source: &str| assemble(source).unwrap_err().errors[0].message.clone();
+    assert_eq!(
+        error(".ORIG x3000\nA .EQU 1\nA .EQU 2"),
+        "duplicate label `A`, first defined at line 2"
+    );
+    assert_eq!(
+        error(".ORIG x3000\nA .SET B\nB .EQU 1"),
+        "undefined label `B`"
+    );
+    assert_eq!(
+        error(".ORIG x3000\nADD R0, R0, START\nSTART NOP"),
+        "operand 3 of ADD must be a number, not an address"
+    );
+}
+
+#[test]
+fn test_assemble_file() {
+    let dir = std::env::temp_dir().join(format!("memevm-asm-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("lib.asm"),
+        "NEWLINE .EQU x0A\n.MACRO PRINTLN\n LD R0, NL\\@\n OUT\n BR SKIP\\@\nNL\\@ .FILL NEWLINE\nSKIP\\@\n.ENDM\n",
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("main.asm"),
+        ".ORIG x3000\n.INCLUDE \"lib.asm\"\nPRINTLN\nPRINTLN\nHALT\nLD R0, MISSING\n.END\n",
+    )
+    .unwrap();
+    let errors = assemble_file(dir.join("main.asm")).unwrap_err().errors;
+    assert_eq!(errors.len(), 1);
+    assert!(errors[0]
+        .to_string()
+        .ends_with("main.asm:6:8: undefined label `MISSING`"));
+
+    std::fs::write(
+        dir.join("main.asm"),
+        ".ORIG x3000\n.INCLUDE \"lib.asm\"\nPRINTLN\nPRINTLN\nHALT\n.END\n",
+    )
+    .unwrap();
+    let program = assemble_file(dir.join("main.asm")).unwrap();
+    let mut vm = crate::vm::VirtualMachine::with_memory(0x10000);
+    let console = crate::console::BufferConsole::new();
+    vm.set_console(Box::new(console.clone()));
+    vm.read_image(&program.image()).unwrap();
+    assert_eq!(vm.run(), crate::vm::StopReason::Halted);
+    assert_eq!(console.take_output(), b"\n\nHALTING\n");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
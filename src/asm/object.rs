@@ -0,0 +1,246 @@
+//! Relocatable objects: assembled modules that the linker can place anywhere and join to
+//! the modules they import from.
+//!
+//! On disk an object is text, one record per line:
+//!
+//! ```text
+//! ; memevm object
+//! origin x3000
+//! words x4800 xF025 x3000
+//! symbol x3000 MAIN
+//! global MAIN
+//! external PRINT
+//! reloc x3000 pc11 PRINT 0
+//! reloc x3002 word
+//! ```
+
+use std::fmt::Write;
+use std::io;
+use std::path::Path;
+
+use super::{Program, SourceLine};
+
+/// How a word refers to an address that is only known once modules are placed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelocationKind {
+    /// The whole word is an address, as in `.FILL LABEL`.
+    Word,
+    /// The low 9 bits are an offset from the incremented PC, as in `LD` or `BR`.
+    PcOffset9,
+    /// The low 11 bits are an offset from the incremented PC, as in `JSR`.
+    PcOffset11,
+}
+
+impl RelocationKind {
+    fn name(self) -> &'static str {
+        match self {
+            RelocationKind::Word => "word",
+            RelocationKind::PcOffset9 => "pc9",
+            RelocationKind::PcOffset11 => "pc11",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "word" => Some(RelocationKind::Word),
+            "pc9" => Some(RelocationKind::PcOffset9),
+            "pc11" => Some(RelocationKind::PcOffset11),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Relocation {
+    /// Address of the word, as assembled.
+    pub addr: u16,
+    pub kind: RelocationKind,
+    /// The `.EXTERNAL` symbol the word refers to, plus `addend`. Without one, the word is an
+    /// address in the module itself, which moves along with it.
+    pub symbol: Option<String>,
+    pub addend: i32,
+}
+
+/// An assembled module: like a `Program`, but with what the linker needs to move it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Object {
+    /// Where the module was assembled to start, with `.ORIG`.
+    pub origin: u16,
+    pub words: Vec<u16>,
+    /// Labels and their addresses, as assembled.
+    pub symbols: Vec<(String, u16)>,
+    /// The labels other modules can use, declared with `.GLOBAL`.
+    pub globals: Vec<String>,
+    /// The labels used from other modules, declared with `.EXTERNAL`.
+    pub externals: Vec<String>,
+    pub relocations: Vec<Relocation>,
+}
+
+impl Object {
+    /// The module as it is, at its own origin. Only for modules that import nothing.
+    pub(super) fn into_program(self, lines: Vec<SourceLine>) -> Program {
+        Program {
+            origin: self.origin,
+            words: self.words,
+            symbols: self.symbols,
+            lines,
+        }
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = format!("; memevm object\norigin x{:04X}\n", self.origin);
+        for chunk in self.words.chunks(8) {
+            text.push_str("words");
+            for word in chunk {
+                let _ = write!(text, " x{:04X}", word);
+            }
+            text.push('\n');
+        }
+        for (name, addr) in &self.symbols {
+            let _ = writeln!(text, "symbol x{:04X} {}", addr, name);
+        }
+        for name in &self.globals {
+            let _ = writeln!(text, "global {}", name);
+        }
+        for name in &self.externals {
+            let _ = writeln!(text, "external {}", name);
+        }
+        for relocation in &self.relocations {
+            let _ = write!(
+                text,
+                "reloc x{:04X} {}",
+                relocation.addr,
+                relocation.kind.name()
+            );
+            if let Some(symbol) = &relocation.symbol {
+                let _ = write!(text, " {} {}", symbol, relocation.addend);
+            }
+            text.push('\n');
+        }
+        text
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut object = Object {
+            origin: 0,
+            words: Vec::new(),
+            symbols: Vec::new(),
+            globals: Vec::new(),
+            externals: Vec::new(),
+            relocations: Vec::new(),
+        };
+        let mut origin = None;
+        for (index, line) in text.lines().enumerate() {
+            let error = |message: &str| format!("line {}: {}", index + 1, message);
+            let line = line.split(';').next().unwrap_or("").trim();
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (record, fields) = match fields.split_first() {
+                Some(split) => split,
+                None => continue,
+            };
+            match (*record, fields) {
+                ("origin", [addr]) => {
+                    origin = Some(hex(addr).ok_or_else(|| error("expected an address"))?);
+                }
+                ("words", words) => {
+                    for word in words {
+                        object
+                            .words
+                            .push(hex(word).ok_or_else(|| error("expected a word"))?);
+                    }
+                }
+                ("symbol", [addr, name]) => {
+                    let addr = hex(addr).ok_or_else(|| error("expected an address"))?;
+                    object.symbols.push((name.to_string(), addr));
+                }
+                ("global", [name]) => object.globals.push(name.to_string()),
+                ("external", [name]) => object.externals.push(name.to_string()),
+                ("reloc", _) if fields.len() >= 2 => {
+                    let addr = hex(fields[0]).ok_or_else(|| error("expected an address"))?;
+                    let kind = RelocationKind::from_name(fields[1])
+                        .ok_or_else(|| error("unknown relocation kind"))?;
+                    let (symbol, addend) = match &fields[2..] {
+                        [] => (None, 0),
+                        [symbol, addend] => (
+                            Some(symbol.to_string()),
+                            addend.parse().map_err(|_| error("expected an addend"))?,
+                        ),
+                        _ => return Err(error("expected a symbol and an addend")),
+                    };
+                    object.relocations.push(Relocation {
+                        addr,
+                        kind,
+                        symbol,
+                        addend,
+                    });
+                }
+                _ => return Err(error("unknown record")),
+            }
+        }
+        object.origin = origin.ok_or_else(|| "missing origin".to_string())?;
+        Ok(object)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)?;
+        Self::parse(&text)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}: {}", path, e)))
+    }
+
+    pub fn write_file<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        std::fs::write(path, self.to_text())
+    }
+}
+
+fn hex(text: &str) -> Option<u16> {
+    if text.starts_with('x') {
+        u16::from_str_radix(&text[1..], 16).ok()
+    } else {
+        None
+    }
+}
+
+#[test]
+fn test_object_format() {
+    let object = super::assemble_object(
+        "        .ORIG x3000
+        .GLOBAL MAIN
+        .EXTERNAL PRINT, TABLE
+MAIN    JSR PRINT
+        LD R0, TABLE+2
+        HALT
+        .FILL MAIN
+        .FILL TABLE-1
+        .END",
+    )
+    .unwrap();
+    assert_eq!(object.words, vec![0x4800, 0x2000, 0xF025, 0x3000, 0x0000]);
+    assert_eq!(object.globals, vec!["MAIN".to_string()]);
+    assert_eq!(
+        object.externals,
+        vec!["PRINT".to_string(), "TABLE".to_string()]
+    );
+    let text = object.to_text();
+    assert_eq!(
+        text,
+        "; memevm object
+origin x3000
+words x4800 x2000 xF025 x3000 x0000
+symbol x3000 MAIN
+global MAIN
+external PRINT
+external TABLE
+reloc x3000 pc11 PRINT 0
+reloc x3001 pc9 TABLE 2
+reloc x3003 word
+reloc x3004 word TABLE -1
+"
+    );
+    assert_eq!(Object::parse(&text), Ok(object));
+    assert_eq!(
+        Object::parse("origin x3000\nreloc x3000 pc12"),
+        Err("line 2: unknown relocation kind".to_string())
+    );
+    assert!(Object::parse("words x0000").is_err());
+}
@@ -0,0 +1,487 @@
+//! Expands `.INCLUDE` and macros, turning the source into the lines the passes work on.
+//!
+//! A macro is defined with
+//!
+//! ```text
+//! .MACRO PUSH reg
+//!         ADD R6, R6, #-1
+//!         STR \reg, R6, #0
+//! .ENDM
+//! ```
+//!
+//! and used like an instruction, `PUSH R0`. In the body, `\name` stands for the argument
+//! given for `name`, and `\@` for a number unique to each expansion, to make labels such as
+//! `LOOP\@` that do not clash between expansions.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use super::lexer::{self, TokenKind};
+use super::{is_label, is_mnemonic, AsmError};
+
+/// Nested includes and macro expansions beyond this depth are taken to be infinite.
+const MAX_DEPTH: usize = 64;
+
+/// Where a line comes from. Lines expanded from a macro are attributed to the line that
+/// uses the macro.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub file: Option<Rc<str>>,
+    pub line: usize,
+    /// The line as written.
+    pub source: Rc<str>,
+}
+
+impl Location {
+    /// Translates a column of `text` into a column of the line as written. There is none
+    /// when `text` is not that line, as with the lines of a macro expansion.
+    pub fn column(&self, text: &str, column: usize) -> Option<usize> {
+        if text == &*self.source {
+            Some(column)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Line {
+    pub location: Location,
+    pub text: String,
+}
+
+#[derive(Debug)]
+struct Macro {
+    params: Vec<String>,
+    body: Vec<String>,
+}
+
+#[derive(Default)]
+struct Preprocessor {
+    macros: HashMap<String, Macro>,
+    /// The macro being defined, and where.
+    defining: Option<(String, Macro, Location)>,
+    expansions: usize,
+    lines: Vec<Line>,
+    errors: Vec<(usize, AsmError)>,
+}
+
+/// Expands `source`, read from `path` if it comes from a file. Includes are looked up
+/// relative to the file that includes them, or to the working directory.
+///
+/// A line in error is left out and the rest is still expanded. Each error comes with the
+/// number of lines expanded before it, to put it in order with the errors of later passes.
+pub fn preprocess(source: &str, path: Option<&Path>) -> (Vec<Line>, Vec<(usize, AsmError)>) {
+    let mut preprocessor = Preprocessor::default();
+    preprocessor.file(source, path, 0);
+    if let Some((name, _, location)) = preprocessor.defining.take() {
+        let error = AsmError::at(&location, None, format!("macro `{}` has no .ENDM", name));
+        preprocessor.errors.push((preprocessor.lines.len(), error));
+    }
+    (preprocessor.lines, preprocessor.errors)
+}
+
+impl Preprocessor {
+    fn file(&mut self, source: &str, path: Option<&Path>, depth: usize) {
+        let file: Option<Rc<str>> = path.map(|path| path.to_string_lossy().into());
+        for (index, text) in source.lines().enumerate() {
+            let location = Location {
+                file: file.clone(),
+                line: index + 1,
+                source: text.into(),
+            };
+            self.checked_line(&location, text, path, depth);
+        }
+    }
+
+    fn checked_line(&mut self, location: &Location, text: &str, path: Option<&Path>, depth: usize) {
+        if let Err(error) = self.line(location, text, path, depth) {
+            self.errors.push((self.lines.len(), error));
+        }
+    }
+
+    fn line(
+        &mut self,
+        location: &Location,
+        text: &str,
+        path: Option<&Path>,
+        depth: usize,
+    ) -> Result<(), AsmError> {
+        let tokens = lexer::tokenize(text)
+            .map_err(|e| AsmError::at(location, location.column(text, e.column), e.message))?;
+        let words: Vec<Option<String>> = tokens
+            .iter()
+            .map(|token| match &token.kind {
+                TokenKind::Word(word) => Some(word.to_ascii_uppercase()),
+                _ => None,
+            })
+            .collect();
+        let first = words.get(0).cloned().unwrap_or(None);
+
+        if let Some((name, mut definition, start)) = self.defining.take() {
+            match first.as_ref().map(String::as_str) {
+                Some(".ENDM") => {
+                    self.macros.insert(name, definition);
+                }
+                Some(".MACRO") => {
+                    // the line is left out, the definition goes on
+                    self.defining = Some((name, definition, start));
+                    return Err(AsmError::at(
+                        location,
+                        location.column(text, tokens[0].column),
+                        "macros cannot be defined inside macros".to_string(),
+                    ));
+                }
+                _ => {
+                    definition.body.push(text.to_string());
+                    self.defining = Some((name, definition, start));
+                }
+            }
+            return Ok(());
+        }
+
+        // the directive or macro name comes first, or after a label
+        let is_keyword = |word: &Option<String>| {
+            word.as_ref().map_or(false, |word| {
+                is_mnemonic(word) || self.macros.contains_key(word)
+            })
+        };
+        let (label, keyword) = match words.as_slice() {
+            [first, ..] if is_keyword(first) => (None, 0),
+            [_, second, ..] if is_keyword(second) => (Some(&tokens[0]), 1),
+            _ => {
+                self.push(location, text);
+                return Ok(());
+            }
+        };
+        let name = words[keyword].clone().unwrap_or_default();
+        let column = location.column(text, tokens[keyword].column);
+        let arguments = &tokens[keyword + 1..];
+        match name.as_str() {
+            ".MACRO" => {
+                if label.is_some() {
+                    return Err(AsmError::at(
+                        location,
+                        column,
+                        ".MACRO cannot be labelled".to_string(),
+                    ));
+                }
+                let mut names = arguments
+                    .iter()
+                    .filter(|token| token.kind != TokenKind::Comma);
+                let name = match names.next().map(|token| &token.kind) {
+                    Some(TokenKind::Word(name)) if is_label(name) && !is_mnemonic(name) => {
+                        name.to_ascii_uppercase()
+                    }
+                    _ => {
+                        return Err(AsmError::at(
+                            location,
+                            column,
+                            ".MACRO expects a name".to_string(),
+                        ));
+                    }
+                };
+                let mut params = Vec::new();
+                for token in names {
+                    match &token.kind {
+                        TokenKind::Word(param) if is_label(param) => params.push(param.clone()),
+                        _ => {
+                            return Err(AsmError::at(
+                                location,
+                                location.column(text, token.column),
+                                "invalid macro parameter".to_string(),
+                            ));
+                        }
+                    }
+                }
+                let definition = Macro {
+                    params,
+                    body: Vec::new(),
+                };
+                self.defining = Some((name, definition, location.clone()));
+            }
+            ".ENDM" => {
+                return Err(AsmError::at(
+                    location,
+                    column,
+                    ".ENDM without .MACRO".to_string(),
+                ));
+            }
+            ".INCLUDE" => {
+                let file = match arguments {
+                    [lexer::Token {
+                        kind: TokenKind::Str(file),
+                        ..
+                    }] if label.is_none() => file,
+                    _ => {
+                        return Err(AsmError::at(
+                            location,
+                            column,
+                            ".INCLUDE expects a file name".to_string(),
+                        ));
+                    }
+                };
+                if depth >= MAX_DEPTH {
+                    return Err(AsmError::at(
+                        location,
+                        column,
+                        "includes nest too deep".to_string(),
+                    ));
+                }
+                let included = match path.and_then(Path::parent) {
+                    Some(dir) => dir.join(file),
+                    None => PathBuf::from(file),
+                };
+                let source = std::fs::read_to_string(&included).map_err(|e| {
+                    AsmError::at(
+                        location,
+                        column,
+                        format!("cannot include {:?}: {}", file, e),
+                    )
+                })?;
+                self.file(&source, Some(&included), depth + 1);
+            }
+            _ if self.macros.contains_key(&name) => {
+                if depth >= MAX_DEPTH {
+                    return Err(AsmError::at(
+                        location,
+                        column,
+                        format!("macro `{}` expands too deep", name),
+                    ));
+                }
+                if let Some(label) = label {
+                    if let TokenKind::Word(label) = &label.kind {
+                        self.push(location, label);
+                    }
+                }
+                // arguments are taken as written, up to the comment
+                let rest: String = match arguments.first() {
+                    Some(token) => text.chars().skip(token.column - 1).collect(),
+                    None => String::new(),
+                };
+                let values = split_arguments(&rest);
+                let definition = &self.macros[&name];
+                if values.len() != definition.params.len() {
+                    return Err(AsmError::at(
+                        location,
+                        column,
+                        format!(
+                            "macro `{}` takes {} argument{}, found {}",
+                            name,
+                            definition.params.len(),
+                            if definition.params.len() == 1 {
+                                ""
+                            } else {
+                                "s"
+                            },
+                            values.len()
+                        ),
+                    ));
+                }
+                self.expansions += 1;
+                let body: Vec<String> = definition
+                    .body
+                    .iter()
+                    .map(|line| substitute(line, &definition.params, &values, self.expansions))
+                    .collect();
+                for line in body {
+                    self.checked_line(location, &line, path, depth + 1);
+                }
+            }
+            _ => self.push(location, text),
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, location: &Location, text: &str) {
+        self.lines.push(Line {
+            location: location.clone(),
+            text: text.to_string(),
+        });
+    }
+}
+
+/// Splits macro arguments at the commas that are not quoted, stopping at a comment.
+fn split_arguments(text: &str) -> Vec<String> {
+    let mut arguments = Vec::new();
+    let mut current = String::new();
+    let mut quote = None;
+    let mut escaped = false;
+    for c in text.chars() {
+        match quote {
+            Some(q) => {
+                if escaped {
+                    escaped = false;
+                } else if c == '\\' {
+                    escaped = true;
+                } else if c == q {
+                    quote = None;
+                }
+            }
+            None if c == ';' => break,
+            None if c == ',' => {
+                arguments.push(current.trim().to_string());
+                current.clear();
+                continue;
+            }
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None => {}
+        }
+        current.push(c);
+    }
+    if !current.trim().is_empty() || !arguments.is_empty() {
+        arguments.push(current.trim().to_string());
+    }
+    arguments
+}
+
+/// Replaces `\param` and `\@` in a line of a macro body.
+fn substitute(line: &str, params: &[String], values: &[String], expansion: usize) -> String {
+    let chars: Vec<char> = line.chars().collect();
+    let mut output = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        if chars[i] == '\\' {
+            if chars.get(i + 1) == Some(&'@') {
+                output.push_str(&expansion.to_string());
+                i += 2;
+                continue;
+            }
+            let end = (i + 1..chars.len())
+                .find(|&j| !(chars[j].is_ascii_alphanumeric() || chars[j] == '_'))
+                .unwrap_or_else(|| chars.len());
+            let name: String = chars[i + 1..end].iter().collect();
+            if let Some(index) = params.iter().position(|param| *param == name) {
+                output.push_str(&values[index]);
+                i = end;
+                continue;
+            }
+        }
+        output.push(chars[i]);
+        i += 1;
+    }
+    output
+}
+
+#[test]
+fn test_macros() {
+    let (lines, errors) = preprocess(
+        ".MACRO PUSH reg
+        ADD R6, R6, #-1
+        STR \\reg, R6, #0
+.ENDM
+.MACRO WAIT count
+        AND R0, R0, #0
+        ADD R0, R0, \\count ; a comment, with a comma
+LOOP\\@  ADD R0, R0, #-1
+        BRp LOOP\\@
+.ENDM
+START   push R1
+        WAIT #3
+        WAIT 'a'",
+        None,
+    );
+    assert!(errors.is_empty());
+    let text: Vec<&str> = lines.iter().map(|line| line.text.trim()).collect();
+    assert_eq!(
+        text,
+        vec![
+            "START",
+            "ADD R6, R6, #-1",
+            "STR R1, R6, #0",
+            "AND R0, R0, #0",
+            "ADD R0, R0, #3 ; a comment, with a comma",
+            "LOOP2  ADD R0, R0, #-1",
+            "BRp LOOP2",
+            "AND R0, R0, #0",
+            "ADD R0, R0, 'a' ; a comment, with a comma",
+            "LOOP3  ADD R0, R0, #-1",
+            "BRp LOOP3",
+        ]
+    );
+    assert_eq!(lines[2].location.line, 11);
+    assert_eq!(lines[10].location.line, 13);
+
+    let error = |source: &str| preprocess(source, None).1[0].1.message.clone();
+    assert_eq!(error(".MACRO M\nNOP"), "macro `M` has no .ENDM");
+    assert_eq!(error(".ENDM"), ".ENDM without .MACRO");
+    assert_eq!(
+        error(".MACRO M a\n.ENDM\nM"),
+        "macro `M` takes 1 argument, found 0"
+    );
+    assert_eq!(error(".MACRO M\nM\n.ENDM\nM"), "macro `M` expands too deep");
+
+    // lines in error are left out and the rest still goes through
+    let (lines, errors) = preprocess(
+        ".ENDM\n.MACRO M\n.MACRO N\n NOP\n.ENDM\nM 1\nM\nLABEL .STRINGZ \"open",
+        None,
+    );
+    let text: Vec<&str> = lines.iter().map(|line| line.text.trim()).collect();
+    assert_eq!(text, vec!["NOP"]);
+    let errors: Vec<(usize, usize, Option<usize>, &str)> = errors
+        .iter()
+        .map(|(index, error)| (*index, error.line, error.column, error.message.as_str()))
+        .collect();
+    assert_eq!(
+        errors,
+        vec![
+            (0, 1, Some(1), ".ENDM without .MACRO"),
+            (0, 3, Some(1), "macros cannot be defined inside macros"),
+            (0, 6, Some(1), "macro `M` takes 0 arguments, found 1"),
+            (1, 8, Some(16), "unterminated string"),
+        ]
+    );
+    assert_eq!(
+        split_arguments("\"a,b\", ',' ; c, d"),
+        vec!["\"a,b\"", "','"]
+    );
+}
+
+#[test]
+fn test_include() {
+    let dir = std::env::temp_dir().join(format!("memevm-include-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("lib")).unwrap();
+    std::fs::write(dir.join("lib/consts.asm"), "SIZE .EQU 4\n").unwrap();
+    std::fs::write(
+        dir.join("lib/lib.asm"),
+        ".INCLUDE \"consts.asm\"\nDOUBLE ADD R0, R0, R0\nRET\n",
+    )
+    .unwrap();
+    let main = dir.join("main.asm");
+    let source = ".ORIG x3000\n.INCLUDE \"lib/lib.asm\"\n.END\n";
+    let (lines, errors) = preprocess(source, Some(&main));
+    assert!(errors.is_empty());
+    let located: Vec<(String, usize, &str)> = lines
+        .iter()
+        .map(|line| {
+            let file = Path::new(&**line.location.file.as_ref().unwrap());
+            (
+                file.file_name().unwrap().to_string_lossy().into_owned(),
+                line.location.line,
+                line.text.as_str(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        located,
+        vec![
+            ("main.asm".to_string(), 1, ".ORIG x3000"),
+            ("consts.asm".to_string(), 1, "SIZE .EQU 4"),
+            ("lib.asm".to_string(), 2, "DOUBLE ADD R0, R0, R0"),
+            ("lib.asm".to_string(), 3, "RET"),
+            ("main.asm".to_string(), 3, ".END"),
+        ]
+    );
+    let (_, errors) = preprocess(".INCLUDE \"missing.asm\"", Some(&main));
+    assert!(errors[0]
+        .1
+        .message
+        .starts_with("cannot include \"missing.asm\""));
+    assert_eq!(errors[0].1.column, Some(1));
+    std::fs::write(dir.join("loop.asm"), ".INCLUDE \"loop.asm\"\n").unwrap();
+    let (_, errors) = preprocess(".INCLUDE \"loop.asm\"", Some(&main));
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].1.message, "includes nest too deep");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
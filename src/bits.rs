@@ -1,7 +1,10 @@
 use num_derive::FromPrimitive;
+use num_traits::FromPrimitive;
 
 use enum_map::EnumMap;
 
+use crate::symbols::SymbolTable;
+
 pub fn sign_extend(mut x: u16, bit_count: usize) -> u16 {
     if ((x >> (bit_count - 1)) & 1) != 0 {
         x |= 0xFFFF << bit_count;
@@ -14,6 +17,7 @@ pub struct DiagnosticStatus {
     pub registers: EnumMap<Register, u16>,
     pub memory_view_range: (usize, usize),
     pub memory_view: Vec<u16>,
+    pub symbols: SymbolTable,
 }
 
 #[derive(Debug, Enum)]
@@ -27,8 +31,9 @@ pub enum Register {
     R6,
     R7,
     PC,
-    COND,
     PSR,
+    SSP, // Saved_SSP, the supervisor stack pointer while in user mode
+    USP, // Saved_USP, the user stack pointer while in supervisor mode
 }
 
 impl Register {
@@ -58,6 +63,229 @@ pub enum Opcode {
     TRAP = 0b1111, // execute trap
 }
 
+/// The second operand of ADD and AND.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AluOperand {
+    Register(u16),
+    Immediate(i16),
+}
+
+/// An instruction with its fields taken apart. Registers are numbered 0 to 7 and offsets
+/// are sign-extended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    Add {
+        dr: u16,
+        sr1: u16,
+        operand: AluOperand,
+    },
+    And {
+        dr: u16,
+        sr1: u16,
+        operand: AluOperand,
+    },
+    /// `nzp` holds the `ConditionFlags` to branch on.
+    Br {
+        nzp: u16,
+        offset: i16,
+    },
+    /// `RET` is `JMP R7`.
+    Jmp {
+        base: u16,
+    },
+    Jsr {
+        offset: i16,
+    },
+    Jsrr {
+        base: u16,
+    },
+    Ld {
+        dr: u16,
+        offset: i16,
+    },
+    Ldi {
+        dr: u16,
+        offset: i16,
+    },
+    Ldr {
+        dr: u16,
+        base: u16,
+        offset: i16,
+    },
+    Lea {
+        dr: u16,
+        offset: i16,
+    },
+    Not {
+        dr: u16,
+        sr: u16,
+    },
+    Rti,
+    St {
+        sr: u16,
+        offset: i16,
+    },
+    Sti {
+        sr: u16,
+        offset: i16,
+    },
+    Str {
+        sr: u16,
+        base: u16,
+        offset: i16,
+    },
+    Trap {
+        vector: u8,
+    },
+}
+
+/// Why a word is not an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The opcode is RES, the only one that names no instruction.
+    Reserved,
+}
+
+/// Takes an instruction word apart. Like the CPU, this ignores bits that an instruction does
+/// not use, so `encode` only gives back `word` if it is in the form the assembler writes.
+pub fn decode(word: u16) -> Result<Instruction, DecodeError> {
+    let r9 = (word >> 9) & 0x7;
+    let r6 = (word >> 6) & 0x7;
+    let offset = |bits: usize| sign_extend(word & ((1 << bits) - 1), bits) as i16;
+    let operand = || {
+        if word & 0x20 != 0 {
+            AluOperand::Immediate(offset(5))
+        } else {
+            AluOperand::Register(word & 0x7)
+        }
+    };
+    let instruction = match Opcode::from_u16(word >> 12) {
+        Some(Opcode::ADD) => Instruction::Add {
+            dr: r9,
+            sr1: r6,
+            operand: operand(),
+        },
+        Some(Opcode::AND) => Instruction::And {
+            dr: r9,
+            sr1: r6,
+            operand: operand(),
+        },
+        Some(Opcode::BR) => Instruction::Br {
+            nzp: r9,
+            offset: offset(9),
+        },
+        Some(Opcode::JMP) => Instruction::Jmp { base: r6 },
+        Some(Opcode::JSR) if word & 0x0800 != 0 => Instruction::Jsr { offset: offset(11) },
+        Some(Opcode::JSR) => Instruction::Jsrr { base: r6 },
+        Some(Opcode::LD) => Instruction::Ld {
+            dr: r9,
+            offset: offset(9),
+        },
+        Some(Opcode::LDI) => Instruction::Ldi {
+            dr: r9,
+            offset: offset(9),
+        },
+        Some(Opcode::LDR) => Instruction::Ldr {
+            dr: r9,
+            base: r6,
+            offset: offset(6),
+        },
+        Some(Opcode::LEA) => Instruction::Lea {
+            dr: r9,
+            offset: offset(9),
+        },
+        Some(Opcode::NOT) => Instruction::Not { dr: r9, sr: r6 },
+        Some(Opcode::RTI) => Instruction::Rti,
+        Some(Opcode::ST) => Instruction::St {
+            sr: r9,
+            offset: offset(9),
+        },
+        Some(Opcode::STI) => Instruction::Sti {
+            sr: r9,
+            offset: offset(9),
+        },
+        Some(Opcode::STR) => Instruction::Str {
+            sr: r9,
+            base: r6,
+            offset: offset(6),
+        },
+        Some(Opcode::TRAP) => Instruction::Trap { vector: word as u8 },
+        // every four-bit opcode is defined, so only RES is left over
+        Some(Opcode::RES) | None => return Err(DecodeError::Reserved),
+    };
+    Ok(instruction)
+}
+
+/// Puts an instruction together the way the assembler writes it: bits it does not use are
+/// clear, except for the low 6 bits of NOT, which are set. Registers and offsets are cut
+/// down to the width of their fields.
+pub fn encode(instruction: &Instruction) -> u16 {
+    let op = |opcode: Opcode| (opcode as u16) << 12;
+    let r9 = |register: u16| (register & 0x7) << 9;
+    let r6 = |register: u16| (register & 0x7) << 6;
+    let offset = |offset: i16, bits: u32| offset as u16 & ((1 << bits) - 1);
+    let operand = |operand: AluOperand| match operand {
+        AluOperand::Register(register) => register & 0x7,
+        AluOperand::Immediate(imm) => 0x20 | offset(imm, 5),
+    };
+    match *instruction {
+        Instruction::Add {
+            dr,
+            sr1,
+            operand: second,
+        } => op(Opcode::ADD) | r9(dr) | r6(sr1) | operand(second),
+        Instruction::And {
+            dr,
+            sr1,
+            operand: second,
+        } => op(Opcode::AND) | r9(dr) | r6(sr1) | operand(second),
+        Instruction::Br { nzp, offset: pc } => op(Opcode::BR) | r9(nzp) | offset(pc, 9),
+        Instruction::Jmp { base } => op(Opcode::JMP) | r6(base),
+        Instruction::Jsr { offset: pc } => op(Opcode::JSR) | 0x0800 | offset(pc, 11),
+        Instruction::Jsrr { base } => op(Opcode::JSR) | r6(base),
+        Instruction::Ld { dr, offset: pc } => op(Opcode::LD) | r9(dr) | offset(pc, 9),
+        Instruction::Ldi { dr, offset: pc } => op(Opcode::LDI) | r9(dr) | offset(pc, 9),
+        Instruction::Ldr {
+            dr,
+            base,
+            offset: rel,
+        } => op(Opcode::LDR) | r9(dr) | r6(base) | offset(rel, 6),
+        Instruction::Lea { dr, offset: pc } => op(Opcode::LEA) | r9(dr) | offset(pc, 9),
+        Instruction::Not { dr, sr } => op(Opcode::NOT) | r9(dr) | r6(sr) | 0x3F,
+        Instruction::Rti => op(Opcode::RTI),
+        Instruction::St { sr, offset: pc } => op(Opcode::ST) | r9(sr) | offset(pc, 9),
+        Instruction::Sti { sr, offset: pc } => op(Opcode::STI) | r9(sr) | offset(pc, 9),
+        Instruction::Str {
+            sr,
+            base,
+            offset: rel,
+        } => op(Opcode::STR) | r9(sr) | r6(base) | offset(rel, 6),
+        Instruction::Trap { vector } => op(Opcode::TRAP) | u16::from(vector),
+    }
+}
+
+pub const PSR_USER_MODE: u16 = 1 << 15;
+pub const PSR_PRIORITY_MASK: u16 = 0x7 << 8;
+pub const PSR_CONDITION_MASK: u16 = 0x7;
+
+// addresses outside of this range are only accessible in supervisor mode
+pub const USER_SPACE: std::ops::Range<u16> = 0x3000..0xFE00;
+
+pub const TRAP_VECTOR_TABLE: u16 = 0x0000;
+pub const EXCEPTION_VECTOR_TABLE: u16 = 0x0100;
+pub const INTERRUPT_VECTOR_TABLE: u16 = 0x0180;
+
+pub enum ExceptionVector {
+    PrivilegeViolation = 0x00, // RTI executed in user mode
+    IllegalOpcode = 0x01,      // RES executed
+    AccessViolation = 0x02,    // user mode access to system space or device registers
+}
+
+pub enum InterruptVector {
+    Keyboard = 0x00, // INTV x80
+    Timer = 0x01,    // INTV x81
+}
+
 pub enum ConditionFlags {
     POS = 1 << 0, // P
     ZRO = 1 << 1, // Z
@@ -67,8 +295,24 @@ pub enum ConditionFlags {
 pub enum MemoryMappedRegister {
     KBSR = 0xFE00,
     KBDR = 0xFE02,
+    DSR = 0xFE04,
+    DDR = 0xFE06,
+    TMCR = 0xFE08,  // timer control
+    TMRR = 0xFE0A,  // timer reload
+    TMCNT = 0xFE0C, // timer count
+    MCR = 0xFFFE,
 }
 
+pub const KBSR_READY: u16 = 1 << 15;
+pub const KBSR_INTERRUPT_ENABLE: u16 = 1 << 14;
+pub const KEYBOARD_PRIORITY: u8 = 4;
+pub const DSR_READY: u16 = 1 << 15;
+pub const MCR_CLOCK_ENABLE: u16 = 1 << 15;
+pub const TIMER_ENABLE: u16 = 1 << 15;
+pub const TIMER_INTERRUPT_ENABLE: u16 = 1 << 14;
+pub const TIMER_EXPIRED: u16 = 1 << 0;
+pub const TIMER_PRIORITY: u8 = 6;
+
 #[derive(FromPrimitive)]
 pub enum TrapCode {
     GetC = 0x20,  // get character from keyboard
@@ -77,4 +321,76 @@ pub enum TrapCode {
     In = 0x23,    // input a string
     PutSp = 0x24, // output a byte string
     Halt = 0x25,  // halt the program
+    Exit = 0x26,  // halt with the exit status in R0
+}
+
+/// Extension traps for file I/O, serviced by the host when a sandbox is installed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileTrapCode {
+    Open = 0x30,  // open the file named by the string at R0, with mode R1
+    Read = 0x31,  // read up to R2 bytes from handle R0 to the buffer at R1
+    Write = 0x32, // write R2 bytes from the buffer at R1 to handle R0
+    Close = 0x33, // close handle R0
+}
+
+#[test]
+fn test_decode_encode_round_trip() {
+    let mut reserved = 0;
+    let mut canonical = 0;
+    for word in 0..=0xFFFF_u16 {
+        let instruction = match decode(word) {
+            Ok(instruction) => instruction,
+            Err(DecodeError::Reserved) => {
+                assert_eq!(word >> 12, Opcode::RES as u16);
+                reserved += 1;
+                continue;
+            }
+        };
+        let encoded = encode(&instruction);
+        // other words come back in the form the assembler writes, meaning the same thing
+        assert_eq!(decode(encoded), Ok(instruction), "{:04X}", word);
+        if encoded == word {
+            canonical += 1;
+        }
+    }
+    assert_eq!(reserved, 0x1000);
+    // the register forms of ADD and AND leave 2 bits clear, BR, LD, LDI, LDR, LEA, ST, STI
+    // and STR use every bit, then come NOT, JMP, JSR, JSRR, RTI and TRAP
+    let alu = 2 * (0x0800 + 0x0800 / 4);
+    assert_eq!(
+        canonical,
+        alu + 8 * 0x1000 + 0x40 + 0x08 + 0x0800 + 0x08 + 1 + 0x100
+    );
+}
+
+#[test]
+fn test_decode() {
+    assert_eq!(
+        decode(0b0001_001_010_1_11111),
+        Ok(Instruction::Add {
+            dr: 1,
+            sr1: 2,
+            operand: AluOperand::Immediate(-1),
+        })
+    );
+    assert_eq!(
+        decode(0b0000_101_100000000),
+        Ok(Instruction::Br {
+            nzp: ConditionFlags::NEG as u16 | ConditionFlags::POS as u16,
+            offset: -256,
+        })
+    );
+    assert_eq!(decode(0x4FFF), Ok(Instruction::Jsr { offset: -1 }));
+    assert_eq!(decode(0x41C0), Ok(Instruction::Jsrr { base: 7 }));
+    assert_eq!(decode(0xF025), Ok(Instruction::Trap { vector: 0x25 }));
+    assert_eq!(decode(0xD000), Err(DecodeError::Reserved));
+    // LDR takes a 6-bit offset
+    assert_eq!(
+        encode(&Instruction::Ldr {
+            dr: 0,
+            base: 6,
+            offset: -32,
+        }),
+        0b0110_000_110_100000
+    );
 }
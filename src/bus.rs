@@ -0,0 +1,163 @@
+use std::fmt;
+use std::ops::RangeInclusive;
+
+use crate::vm::VmError;
+
+/// Something that answers to a range of addresses on the `Bus`. Offsets are relative to the
+/// start of the range the device is mapped at.
+pub trait Device: fmt::Debug {
+    /// Name shown in the memory map.
+    fn name(&self) -> &str;
+
+    /// Reads a word, with whatever side effects the device has on reads.
+    fn read(&mut self, offset: u16) -> u16;
+
+    fn write(&mut self, offset: u16, value: u16);
+
+    /// Reads a word without side effects, for debuggers and diagnostics.
+    fn peek(&self, offset: u16) -> u16;
+
+    /// Called between instructions. Returns the vector and priority of the interrupt the
+    /// device is requesting, if any.
+    fn interrupt(&mut self) -> Option<(u8, u8)> {
+        None
+    }
+
+    /// Called after every instruction with the number of cycles it took.
+    fn tick(&mut self, _cycles: u64) {}
+}
+
+/// Plain memory.
+pub struct Ram {
+    words: Box<[u16]>,
+}
+
+impl Ram {
+    pub fn with_size(words: usize) -> Self {
+        Ram {
+            words: vec![0; words].into_boxed_slice(),
+        }
+    }
+}
+
+impl fmt::Debug for Ram {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Ram({} words)", self.words.len())
+    }
+}
+
+impl Device for Ram {
+    fn name(&self) -> &str {
+        "RAM"
+    }
+
+    fn read(&mut self, offset: u16) -> u16 {
+        self.peek(offset)
+    }
+
+    fn write(&mut self, offset: u16, value: u16) {
+        self.words[offset as usize] = value;
+    }
+
+    fn peek(&self, offset: u16) -> u16 {
+        self.words[offset as usize]
+    }
+}
+
+#[derive(Debug)]
+struct Mapping {
+    range: RangeInclusive<u16>,
+    device: Box<dyn Device>,
+}
+
+/// Routes memory accesses to the devices mapped in the address space. A device mapped later
+/// takes precedence where ranges overlap, which is how the device registers are carved out
+/// of RAM.
+#[derive(Debug, Default)]
+pub struct Bus {
+    mappings: Vec<Mapping>,
+}
+
+impl Bus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn map(&mut self, range: RangeInclusive<u16>, device: Box<dyn Device>) {
+        debug!(
+            "MAP {:04X}-{:04X}: {}",
+            range.start(),
+            range.end(),
+            device.name()
+        );
+        self.mappings.push(Mapping { range, device });
+    }
+
+    /// Lists the mapped ranges in the order they were mapped.
+    pub fn memory_map(&self) -> Vec<(RangeInclusive<u16>, &str)> {
+        self.mappings
+            .iter()
+            .map(|mapping| (mapping.range.clone(), mapping.device.name()))
+            .collect()
+    }
+
+    fn find(&self, addr: u16) -> Option<usize> {
+        self.mappings
+            .iter()
+            .rposition(|mapping| mapping.range.contains(&addr))
+    }
+
+    fn locate(&mut self, addr: u16) -> Result<(&mut dyn Device, u16), VmError> {
+        let index = self.find(addr).ok_or(VmError::AccessViolation(addr))?;
+        let mapping = &mut self.mappings[index];
+        Ok((&mut *mapping.device, addr - mapping.range.start()))
+    }
+
+    pub fn read(&mut self, addr: u16) -> Result<u16, VmError> {
+        let (device, offset) = self.locate(addr)?;
+        Ok(device.read(offset))
+    }
+
+    pub fn write(&mut self, addr: u16, value: u16) -> Result<(), VmError> {
+        let (device, offset) = self.locate(addr)?;
+        device.write(offset, value);
+        Ok(())
+    }
+
+    pub fn peek(&self, addr: u16) -> Result<u16, VmError> {
+        let index = self.find(addr).ok_or(VmError::AccessViolation(addr))?;
+        let mapping = &self.mappings[index];
+        Ok(mapping.device.peek(addr - mapping.range.start()))
+    }
+
+    /// Collects the interrupts requested by all devices.
+    pub fn interrupts(&mut self) -> Vec<(u8, u8)> {
+        self.mappings
+            .iter_mut()
+            .filter_map(|mapping| mapping.device.interrupt())
+            .collect()
+    }
+
+    pub fn tick(&mut self, cycles: u64) {
+        for mapping in &mut self.mappings {
+            mapping.device.tick(cycles);
+        }
+    }
+}
+
+#[test]
+fn test_bus_routing() {
+    let mut bus = Bus::new();
+    bus.map(0x0000..=0x0FFF, Box::new(Ram::with_size(0x1000)));
+    bus.map(0x0800..=0x0801, Box::new(Ram::with_size(2)));
+    bus.write(0x0010, 1).unwrap();
+    bus.write(0x0801, 2).unwrap();
+    assert_eq!(bus.read(0x0010), Ok(1));
+    assert_eq!(bus.peek(0x0801), Ok(2));
+    assert_eq!(bus.peek(0x0802), Ok(0));
+    assert_eq!(bus.read(0x1000), Err(VmError::AccessViolation(0x1000)));
+    assert_eq!(
+        bus.memory_map(),
+        vec![(0x0000..=0x0FFF, "RAM"), (0x0800..=0x0801, "RAM")]
+    );
+}
@@ -0,0 +1,369 @@
+//! Instruction-level conformance tests against the LC-3 ISA, as specified in appendix A of
+//! Patt & Patel. Every opcode is executed in isolation on a fresh machine in supervisor
+//! mode, checking register results, condition codes, offset limits and address wraparound.
+
+use crate::bits::{ExceptionVector, Opcode, Register, EXCEPTION_VECTOR_TABLE, PSR_USER_MODE};
+use crate::vm::{StopReason, VirtualMachine, VmError};
+
+/// Encodes `opcode` with the given fields, from the most significant down.
+fn op(opcode: Opcode, fields: &[(u16, u16)]) -> u16 {
+    let mut instr = (opcode as u16) << 12;
+    let mut shift = 12;
+    for &(value, width) in fields {
+        shift -= width;
+        instr |= (value & ((1 << width) - 1)) << shift;
+    }
+    instr
+}
+
+fn reg(r: u16) -> (u16, u16) {
+    (r, 3)
+}
+
+fn imm(value: i16, width: u16) -> (u16, u16) {
+    (value as u16, width)
+}
+
+/// A machine about to execute `instr` at `pc`.
+fn machine(pc: u16, instr: u16) -> VirtualMachine {
+    let mut vm = VirtualMachine::with_memory(0x10000);
+    vm.write_memory(pc, instr).unwrap();
+    vm.set_register(Register::PC, pc);
+    vm
+}
+
+fn execute(vm: &mut VirtualMachine) {
+    assert_eq!(vm.run_for(1), StopReason::InstructionLimit);
+}
+
+fn condition(vm: &VirtualMachine) -> &'static str {
+    match vm.register(Register::PSR) & 0x7 {
+        0b100 => "n",
+        0b010 => "z",
+        0b001 => "p",
+        other => panic!("invalid condition codes {:03b}", other),
+    }
+}
+
+#[test]
+fn test_add() {
+    let cases: &[(u16, u16, u16, &str)] = &[
+        (1, 2, 3, "p"),
+        (0xFFFF, 1, 0, "z"),
+        (0x7FFF, 1, 0x8000, "n"), // overflow wraps
+        (0x8000, 0x8000, 0, "z"),
+    ];
+    for &(a, b, sum, cc) in cases {
+        let mut vm = machine(0x3000, op(Opcode::ADD, &[reg(2), reg(0), (0, 3), reg(1)]));
+        vm.set_register(Register::R0, a);
+        vm.set_register(Register::R1, b);
+        execute(&mut vm);
+        assert_eq!(vm.register(Register::R2), sum);
+        assert_eq!(condition(&vm), cc);
+    }
+
+    for &(value, sum, cc) in &[(15, 20, "p"), (-16, 0xFFF5, "n"), (-5, 0, "z")] {
+        let mut vm = machine(
+            0x3000,
+            op(Opcode::ADD, &[reg(3), reg(3), (1, 1), imm(value, 5)]),
+        );
+        vm.set_register(Register::R3, 5);
+        execute(&mut vm);
+        assert_eq!(vm.register(Register::R3), sum);
+        assert_eq!(condition(&vm), cc);
+    }
+}
+
+#[test]
+fn test_and() {
+    let mut vm = machine(0x3000, op(Opcode::AND, &[reg(2), reg(0), (0, 3), reg(1)]));
+    vm.set_register(Register::R0, 0xF0F0);
+    vm.set_register(Register::R1, 0xFF00);
+    execute(&mut vm);
+    assert_eq!(vm.register(Register::R2), 0xF000);
+    assert_eq!(condition(&vm), "n");
+
+    // the idiomatic way of clearing a register must set Z
+    let mut vm = machine(
+        0x3000,
+        op(Opcode::AND, &[reg(0), reg(0), (1, 1), imm(0, 5)]),
+    );
+    vm.set_register(Register::R0, 0x1234);
+    execute(&mut vm);
+    assert_eq!(vm.register(Register::R0), 0);
+    assert_eq!(condition(&vm), "z");
+
+    // imm5 is sign extended
+    let mut vm = machine(
+        0x3000,
+        op(Opcode::AND, &[reg(0), reg(1), (1, 1), imm(-16, 5)]),
+    );
+    vm.set_register(Register::R1, 0x7FFF);
+    execute(&mut vm);
+    assert_eq!(vm.register(Register::R0), 0x7FF0);
+    assert_eq!(condition(&vm), "p");
+}
+
+#[test]
+fn test_not() {
+    for &(value, result, cc) in &[(0, 0xFFFF, "n"), (0xFFFF, 0, "z"), (0x8000, 0x7FFF, "p")] {
+        let mut vm = machine(0x3000, op(Opcode::NOT, &[reg(4), reg(5), (0x3F, 6)]));
+        vm.set_register(Register::R5, value);
+        execute(&mut vm);
+        assert_eq!(vm.register(Register::R4), result);
+        assert_eq!(condition(&vm), cc);
+    }
+}
+
+#[test]
+fn test_br() {
+    // every combination of nzp against every condition
+    for mask in 0..8 {
+        for &(flag, cc) in &[(0b100, "n"), (0b010, "z"), (0b001, "p")] {
+            let mut vm = machine(0x3000, op(Opcode::BR, &[(mask, 3), imm(5, 9)]));
+            vm.set_register(Register::PSR, flag);
+            execute(&mut vm);
+            let taken = mask & flag != 0;
+            assert_eq!(
+                vm.register(Register::PC),
+                if taken { 0x3006 } else { 0x3001 },
+                "BR mask {:03b} with {}",
+                mask,
+                cc
+            );
+            // branches leave the condition codes alone
+            assert_eq!(condition(&vm), cc);
+        }
+    }
+
+    // offset limits, and wraparound in both directions
+    let cases: &[(u16, i16, u16)] = &[
+        (0x3000, 255, 0x3100),
+        (0x3000, -256, 0x2F01),
+        (0xFFF0, 0x20, 0x0011),
+        (0x0010, -256, 0xFF11),
+    ];
+    for &(pc, offset, target) in cases {
+        let mut vm = machine(pc, op(Opcode::BR, &[(0b111, 3), imm(offset, 9)]));
+        vm.set_register(Register::PSR, 0b010);
+        execute(&mut vm);
+        assert_eq!(vm.register(Register::PC), target);
+    }
+}
+
+#[test]
+fn test_jmp_and_ret() {
+    let mut vm = machine(0x3000, op(Opcode::JMP, &[(0, 3), reg(3), (0, 6)]));
+    vm.set_register(Register::R3, 0x4000);
+    execute(&mut vm);
+    assert_eq!(vm.register(Register::PC), 0x4000);
+
+    let mut vm = machine(0x3000, op(Opcode::JMP, &[(0, 3), reg(7), (0, 6)]));
+    vm.set_register(Register::R7, 0x1234);
+    execute(&mut vm);
+    assert_eq!(vm.register(Register::PC), 0x1234);
+}
+
+#[test]
+fn test_jsr() {
+    let cases: &[(u16, i16, u16)] = &[
+        (0x3000, 1023, 0x3400),
+        (0x3000, -1024, 0x2C01),
+        (0xFFF0, 0x20, 0x0011),
+    ];
+    for &(pc, offset, target) in cases {
+        let mut vm = machine(pc, op(Opcode::JSR, &[(1, 1), imm(offset, 11)]));
+        vm.set_register(Register::PSR, 0b010);
+        execute(&mut vm);
+        assert_eq!(vm.register(Register::PC), target);
+        assert_eq!(vm.register(Register::R7), pc.wrapping_add(1));
+        assert_eq!(condition(&vm), "z");
+    }
+
+    let mut vm = machine(0x3000, op(Opcode::JSR, &[(0, 3), reg(2), (0, 6)]));
+    vm.set_register(Register::R2, 0x5000);
+    execute(&mut vm);
+    assert_eq!(vm.register(Register::PC), 0x5000);
+    assert_eq!(vm.register(Register::R7), 0x3001);
+
+    // JSRR R7 jumps to the old value of R7
+    let mut vm = machine(0x3000, op(Opcode::JSR, &[(0, 3), reg(7), (0, 6)]));
+    vm.set_register(Register::R7, 0x5000);
+    execute(&mut vm);
+    assert_eq!(vm.register(Register::PC), 0x5000);
+    assert_eq!(vm.register(Register::R7), 0x3001);
+}
+
+#[test]
+fn test_ld() {
+    let cases: &[(u16, i16, u16)] = &[
+        (0x3000, 255, 0x3100),
+        (0x3000, -256, 0x2F01),
+        (0xFFF0, 0x20, 0x0011),
+    ];
+    for &(pc, offset, addr) in cases {
+        for &(value, cc) in &[(0x8000, "n"), (0, "z"), (42, "p")] {
+            let mut vm = machine(pc, op(Opcode::LD, &[reg(1), imm(offset, 9)]));
+            vm.write_memory(addr, value).unwrap();
+            execute(&mut vm);
+            assert_eq!(vm.register(Register::R1), value);
+            assert_eq!(condition(&vm), cc);
+        }
+    }
+}
+
+#[test]
+fn test_ldi() {
+    let cases: &[(u16, i16, u16)] = &[
+        (0x3000, 255, 0x3100),
+        (0x3000, -256, 0x2F01),
+        (0xFFF0, 0x20, 0x0011),
+        (0x0010, -256, 0xFF11),
+    ];
+    for &(pc, offset, pointer) in cases {
+        let mut vm = machine(pc, op(Opcode::LDI, &[reg(2), imm(offset, 9)]));
+        vm.write_memory(pointer, 0x4000).unwrap();
+        vm.write_memory(0x4000, 0xBEEF).unwrap();
+        execute(&mut vm);
+        assert_eq!(vm.register(Register::R2), 0xBEEF);
+        assert_eq!(condition(&vm), "n");
+    }
+}
+
+#[test]
+fn test_ldr() {
+    let cases: &[(u16, i16, u16)] = &[
+        (0x4000, 31, 0x401F),
+        (0x4000, -32, 0x3FE0),
+        (0x4000, 16, 0x4010), // bit 4 set, positive
+        (0xFFF0, 0x1F, 0x000F),
+        (0x0010, -32, 0xFFF0),
+    ];
+    for &(base, offset, addr) in cases {
+        let mut vm = machine(0x3000, op(Opcode::LDR, &[reg(0), reg(6), imm(offset, 6)]));
+        vm.set_register(Register::R6, base);
+        vm.write_memory(addr, 7).unwrap();
+        execute(&mut vm);
+        assert_eq!(vm.register(Register::R0), 7, "LDR R0, R6, #{}", offset);
+        assert_eq!(condition(&vm), "p");
+    }
+}
+
+#[test]
+fn test_lea() {
+    let cases: &[(u16, i16, u16, &str)] = &[
+        (0x3000, 255, 0x3100, "p"),
+        (0x3000, -256, 0x2F01, "p"),
+        (0xFFF0, 0x20, 0x0011, "p"),
+        (0x0010, -256, 0xFF11, "n"),
+        (0xFFFF, 0, 0x0000, "z"),
+    ];
+    for &(pc, offset, addr, cc) in cases {
+        let mut vm = machine(pc, op(Opcode::LEA, &[reg(5), imm(offset, 9)]));
+        execute(&mut vm);
+        assert_eq!(vm.register(Register::R5), addr);
+        assert_eq!(condition(&vm), cc);
+    }
+}
+
+#[test]
+fn test_stores() {
+    let cases: &[(u16, i16, u16)] = &[
+        (0x3000, 255, 0x3100),
+        (0x3000, -256, 0x2F01),
+        (0xFFF0, 0x20, 0x0011),
+    ];
+    for &(pc, offset, addr) in cases {
+        let mut vm = machine(pc, op(Opcode::ST, &[reg(3), imm(offset, 9)]));
+        vm.set_register(Register::R3, 0x1234);
+        vm.set_register(Register::PSR, 0b001);
+        execute(&mut vm);
+        assert_eq!(vm.read_memory(addr), Ok(0x1234));
+        // stores leave the condition codes alone
+        assert_eq!(condition(&vm), "p");
+
+        let mut vm = machine(pc, op(Opcode::STI, &[reg(3), imm(offset, 9)]));
+        vm.set_register(Register::R3, 0x1234);
+        vm.set_register(Register::PSR, 0b001);
+        vm.write_memory(addr, 0x4000).unwrap();
+        execute(&mut vm);
+        assert_eq!(vm.read_memory(0x4000), Ok(0x1234));
+        assert_eq!(condition(&vm), "p");
+    }
+
+    let cases: &[(u16, i16, u16)] = &[
+        (0x4000, 31, 0x401F),
+        (0x4000, -32, 0x3FE0),
+        (0x0010, -32, 0xFFF0),
+    ];
+    for &(base, offset, addr) in cases {
+        let mut vm = machine(0x3000, op(Opcode::STR, &[reg(1), reg(2), imm(offset, 6)]));
+        vm.set_register(Register::R1, 0xABCD);
+        vm.set_register(Register::R2, base);
+        vm.set_register(Register::PSR, 0b001);
+        execute(&mut vm);
+        assert_eq!(vm.read_memory(addr), Ok(0xABCD));
+        assert_eq!(condition(&vm), "p");
+    }
+}
+
+#[test]
+fn test_trap() {
+    // a trap enters supervisor mode through the trap vector table, pushing PSR and PC
+    let mut vm = machine(0x3000, op(Opcode::TRAP, &[(0, 4), (0x40, 8)]));
+    vm.write_memory(0x0040, 0x1000).unwrap();
+    vm.set_register(Register::PSR, PSR_USER_MODE | 0b001);
+    vm.set_register(Register::R6, 0xFDFF);
+    vm.set_register(Register::SSP, 0x2FFF);
+    execute(&mut vm);
+    assert_eq!(vm.register(Register::PC), 0x1000);
+    assert_eq!(vm.register(Register::PSR) & PSR_USER_MODE, 0);
+    assert_eq!(vm.register(Register::R6), 0x2FFD);
+    assert_eq!(vm.register(Register::USP), 0xFDFF);
+    assert_eq!(vm.read_memory(0x2FFD), Ok(0x3001));
+    assert_eq!(vm.read_memory(0x2FFE), Ok(PSR_USER_MODE | 0b001));
+}
+
+#[test]
+fn test_rti() {
+    // returns to user mode, restoring PC, PSR and both stack pointers
+    let mut vm = machine(0x1000, op(Opcode::RTI, &[(0, 12)]));
+    vm.set_register(Register::R6, 0x2FFD);
+    vm.set_register(Register::USP, 0xFDFF);
+    vm.write_memory(0x2FFD, 0x3001).unwrap();
+    vm.write_memory(0x2FFE, PSR_USER_MODE | 0b100).unwrap();
+    execute(&mut vm);
+    assert_eq!(vm.register(Register::PC), 0x3001);
+    assert_eq!(vm.register(Register::PSR), PSR_USER_MODE | 0b100);
+    assert_eq!(vm.register(Register::R6), 0xFDFF);
+    assert_eq!(vm.register(Register::SSP), 0x2FFF);
+
+    // in user mode it is a privilege violation
+    let mut vm = machine(0x3000, op(Opcode::RTI, &[(0, 12)]));
+    vm.set_register(Register::PSR, PSR_USER_MODE);
+    match vm.run_for(1) {
+        StopReason::Fault(fault) => assert_eq!(fault.error, VmError::PrivilegeViolation),
+        other => panic!("unexpected stop reason {:?}", other),
+    }
+}
+
+#[test]
+fn test_reserved_opcode() {
+    let mut vm = machine(0x3000, op(Opcode::RES, &[(0, 12)]));
+    match vm.run_for(1) {
+        StopReason::Fault(fault) => {
+            assert_eq!(fault.error, VmError::ReservedOpcode);
+            assert_eq!(fault.pc, 0x3000);
+        }
+        other => panic!("unexpected stop reason {:?}", other),
+    }
+
+    // with a handler installed, it raises the illegal opcode exception
+    let mut vm = machine(0x3000, op(Opcode::RES, &[(0, 12)]));
+    let entry = EXCEPTION_VECTOR_TABLE + ExceptionVector::IllegalOpcode as u16;
+    vm.write_memory(entry, 0x1000).unwrap();
+    vm.set_register(Register::R6, 0x3000);
+    execute(&mut vm);
+    assert_eq!(vm.register(Register::PC), 0x1000);
+    assert_eq!(vm.read_memory(0x2FFE), Ok(0x3001));
+}
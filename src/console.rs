@@ -0,0 +1,248 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+/// A console shared by the VM's I/O traps and the keyboard and display devices.
+pub type SharedConsole = Rc<RefCell<Box<dyn Console>>>;
+
+/// The keyboard and display the guest talks to, through the I/O traps and the keyboard
+/// registers.
+pub trait Console: fmt::Debug {
+    /// Returns true if `read` can return without waiting, either with a key or because the
+    /// input has ended.
+    fn poll(&mut self) -> bool;
+
+    /// Returns the next key, or `None` once the input has ended.
+    fn read(&mut self) -> Option<u8>;
+
+    fn write(&mut self, bytes: &[u8]);
+}
+
+/// Writes `bytes` to stdout straight away.
+pub fn write_stdout(bytes: &[u8]) {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    let _ = stdout.write_all(bytes).and_then(|_| stdout.flush());
+}
+
+/// The process's own stdin and stdout. The first time the guest looks for a key, a
+/// terminal on stdin is put in non-canonical mode without echo, so that keys arrive as
+/// soon as they are pressed. The original settings come back when the console is
+/// dropped, the process panics, or it is stopped by SIGINT or SIGTERM.
+#[derive(Default)]
+pub struct TerminalConsole {
+    raw_mode_checked: bool,
+    #[cfg(unix)]
+    original_settings: Option<libc::termios>,
+}
+
+impl TerminalConsole {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[cfg(unix)]
+    fn enter_raw_mode(&mut self) {
+        use std::sync::Once;
+        static RESTORE_ON_EXIT: Once = Once::new();
+
+        if self.raw_mode_checked {
+            return;
+        }
+        self.raw_mode_checked = true;
+        let original = unsafe {
+            let mut original: libc::termios = std::mem::zeroed();
+            if libc::isatty(libc::STDIN_FILENO) == 0
+                || libc::tcgetattr(libc::STDIN_FILENO, &mut original) != 0
+            {
+                return;
+            }
+            original
+        };
+        let mut raw = original;
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO);
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) } != 0 {
+            return;
+        }
+        self.original_settings = Some(original);
+        RESTORE_ON_EXIT.call_once(|| {
+            let previous_hook = std::panic::take_hook();
+            std::panic::set_hook(Box::new(move |info| {
+                restore_terminal(&original);
+                previous_hook(info);
+            }));
+            unsafe {
+                SIGNAL_SETTINGS = Some(original);
+                let handler = restore_and_reraise as extern "C" fn(libc::c_int);
+                libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+                libc::signal(libc::SIGTERM, handler as libc::sighandler_t);
+            }
+        });
+    }
+
+    #[cfg(not(unix))]
+    fn enter_raw_mode(&mut self) {
+        self.raw_mode_checked = true;
+    }
+}
+
+#[cfg(unix)]
+fn restore_terminal(settings: &libc::termios) {
+    unsafe {
+        libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, settings);
+    }
+}
+
+/// The settings `restore_and_reraise` puts back, written once before it is installed.
+#[cfg(unix)]
+static mut SIGNAL_SETTINGS: Option<libc::termios> = None;
+
+/// Restores the terminal, then lets the signal kill the process as it would have without
+/// the handler. Only async-signal-safe calls are made here.
+#[cfg(unix)]
+extern "C" fn restore_and_reraise(signal: libc::c_int) {
+    unsafe {
+        if let Some(settings) = SIGNAL_SETTINGS {
+            restore_terminal(&settings);
+        }
+        libc::signal(signal, libc::SIG_DFL);
+        libc::raise(signal);
+    }
+}
+
+impl Drop for TerminalConsole {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        {
+            if let Some(original) = self.original_settings.take() {
+                restore_terminal(&original);
+            }
+        }
+    }
+}
+
+impl fmt::Debug for TerminalConsole {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TerminalConsole")
+            .field("raw_mode_checked", &self.raw_mode_checked)
+            .finish()
+    }
+}
+
+impl Console for TerminalConsole {
+    #[cfg(unix)]
+    fn poll(&mut self) -> bool {
+        self.enter_raw_mode();
+        let mut stdin = libc::pollfd {
+            fd: libc::STDIN_FILENO,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // end of input and errors also count as readable, `read` reports them
+        unsafe { libc::poll(&mut stdin, 1, 0) != 0 }
+    }
+
+    #[cfg(not(unix))]
+    fn poll(&mut self) -> bool {
+        true
+    }
+
+    #[cfg(unix)]
+    fn read(&mut self) -> Option<u8> {
+        // stdin is read unbuffered, or buffered keys would be invisible to `poll`
+        self.enter_raw_mode();
+        let mut byte = 0u8;
+        loop {
+            match unsafe { libc::read(libc::STDIN_FILENO, &mut byte as *mut u8 as *mut _, 1) } {
+                1 => return Some(byte),
+                -1 if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => {}
+                _ => return None,
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn read(&mut self) -> Option<u8> {
+        use std::io::Read;
+        io::stdin().lock().bytes().next().and_then(Result::ok)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        write_stdout(bytes);
+    }
+}
+
+#[derive(Debug, Default)]
+struct Buffers {
+    input: VecDeque<u8>,
+    input_closed: bool,
+    output: Vec<u8>,
+}
+
+/// In-memory input and output. Clones share the same buffers, so a clone can be kept to
+/// feed keys to and collect output from a console installed on a `VirtualMachine`.
+#[derive(Debug, Default, Clone)]
+pub struct BufferConsole {
+    buffers: Rc<RefCell<Buffers>>,
+}
+
+impl BufferConsole {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_input(input: &[u8]) -> Self {
+        let console = Self::new();
+        console.push_input(input);
+        console
+    }
+
+    pub fn push_input(&self, input: &[u8]) {
+        self.buffers.borrow_mut().input.extend(input);
+    }
+
+    /// Marks the end of the input. Once the queued keys are consumed, reads report the end
+    /// of input instead of waiting for more.
+    pub fn close_input(&self) {
+        self.buffers.borrow_mut().input_closed = true;
+    }
+
+    pub fn take_output(&self) -> Vec<u8> {
+        std::mem::replace(&mut self.buffers.borrow_mut().output, Vec::new())
+    }
+}
+
+impl Console for BufferConsole {
+    fn poll(&mut self) -> bool {
+        let buffers = self.buffers.borrow();
+        !buffers.input.is_empty() || buffers.input_closed
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        self.buffers.borrow_mut().input.pop_front()
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.buffers.borrow_mut().output.extend_from_slice(bytes);
+    }
+}
+
+#[test]
+fn test_buffer_console() {
+    let mut console = BufferConsole::with_input(b"a");
+    let handle = console.clone();
+    assert!(console.poll());
+    assert_eq!(console.read(), Some(b'a'));
+    assert!(!console.poll());
+    handle.close_input();
+    assert!(console.poll());
+    assert_eq!(console.read(), None);
+
+    console.write(b"hello");
+    assert_eq!(handle.take_output(), b"hello");
+    assert!(handle.take_output().is_empty());
+}
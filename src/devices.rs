@@ -0,0 +1,254 @@
+use crate::bits::{
+    InterruptVector, MemoryMappedRegister, DSR_READY, KBSR_INTERRUPT_ENABLE, KBSR_READY,
+    KEYBOARD_PRIORITY, TIMER_ENABLE, TIMER_EXPIRED, TIMER_INTERRUPT_ENABLE, TIMER_PRIORITY,
+};
+use crate::bus::Device;
+use crate::console::SharedConsole;
+
+// offsets of the registers after the first of each device, which is at offset 0
+const KBDR_OFFSET: u16 = MemoryMappedRegister::KBDR as u16 - MemoryMappedRegister::KBSR as u16;
+const DDR_OFFSET: u16 = MemoryMappedRegister::DDR as u16 - MemoryMappedRegister::DSR as u16;
+const TMRR_OFFSET: u16 = MemoryMappedRegister::TMRR as u16 - MemoryMappedRegister::TMCR as u16;
+const TMCNT_OFFSET: u16 = MemoryMappedRegister::TMCNT as u16 - MemoryMappedRegister::TMCR as u16;
+
+/// KBSR and KBDR, fed by the console.
+#[derive(Debug)]
+pub struct Keyboard {
+    console: SharedConsole,
+    kbsr: u16,
+    kbdr: u16,
+}
+
+impl Keyboard {
+    pub fn new(console: SharedConsole) -> Self {
+        Keyboard {
+            console,
+            kbsr: 0,
+            kbdr: 0,
+        }
+    }
+
+    /// Moves the next key into KBDR and sets the ready bit, unless a key is already waiting
+    /// to be read.
+    fn latch_key(&mut self) {
+        if self.kbsr & KBSR_READY != 0 {
+            return;
+        }
+        let mut console = self.console.borrow_mut();
+        if !console.poll() {
+            return;
+        }
+        if let Some(byte) = console.read() {
+            self.kbsr |= KBSR_READY;
+            self.kbdr = u16::from(byte);
+        }
+    }
+}
+
+impl Device for Keyboard {
+    fn name(&self) -> &str {
+        "keyboard"
+    }
+
+    fn read(&mut self, offset: u16) -> u16 {
+        match offset {
+            0 => self.latch_key(),
+            // reading the data register clears the ready bit
+            KBDR_OFFSET => self.kbsr &= !KBSR_READY,
+            _ => {}
+        }
+        self.peek(offset)
+    }
+
+    fn write(&mut self, offset: u16, value: u16) {
+        // only the interrupt enable bit is writable
+        if offset == 0 {
+            self.kbsr = (self.kbsr & !KBSR_INTERRUPT_ENABLE) | (value & KBSR_INTERRUPT_ENABLE);
+        }
+    }
+
+    fn peek(&self, offset: u16) -> u16 {
+        match offset {
+            0 => self.kbsr,
+            KBDR_OFFSET => self.kbdr,
+            _ => 0,
+        }
+    }
+
+    /// The keyboard interrupts for as long as a key is ready and interrupts are enabled.
+    fn interrupt(&mut self) -> Option<(u8, u8)> {
+        if self.kbsr & KBSR_INTERRUPT_ENABLE == 0 {
+            return None;
+        }
+        self.latch_key();
+        if self.kbsr & KBSR_READY != 0 {
+            Some((InterruptVector::Keyboard as u8, KEYBOARD_PRIORITY))
+        } else {
+            None
+        }
+    }
+}
+
+/// DSR and DDR. Characters written to DDR go straight to the console, so the display is
+/// always ready.
+#[derive(Debug)]
+pub struct Display {
+    console: SharedConsole,
+    ddr: u16,
+}
+
+impl Display {
+    pub fn new(console: SharedConsole) -> Self {
+        Display { console, ddr: 0 }
+    }
+}
+
+impl Device for Display {
+    fn name(&self) -> &str {
+        "display"
+    }
+
+    fn read(&mut self, offset: u16) -> u16 {
+        self.peek(offset)
+    }
+
+    fn write(&mut self, offset: u16, value: u16) {
+        if offset == DDR_OFFSET {
+            self.ddr = value;
+            self.console.borrow_mut().write(&[value as u8]);
+        }
+    }
+
+    fn peek(&self, offset: u16) -> u16 {
+        match offset {
+            0 => DSR_READY,
+            DDR_OFFSET => self.ddr,
+            _ => 0,
+        }
+    }
+}
+
+/// An interval timer, with control (TMCR), reload (TMRR) and count (TMCNT) registers.
+/// While enabled, the count goes down by one every cycle. When it reaches zero the expired
+/// bit is set and the count starts over from the reload value, or the timer stops if that
+/// is zero. With interrupts enabled, the timer interrupts for as long as it is expired;
+/// writing TMCR acknowledges it.
+#[derive(Debug, Default)]
+pub struct Timer {
+    control: u16,
+    reload: u16,
+    count: u16,
+}
+
+impl Device for Timer {
+    fn name(&self) -> &str {
+        "timer"
+    }
+
+    fn read(&mut self, offset: u16) -> u16 {
+        self.peek(offset)
+    }
+
+    fn write(&mut self, offset: u16, value: u16) {
+        match offset {
+            0 => self.control = value & (TIMER_ENABLE | TIMER_INTERRUPT_ENABLE),
+            TMRR_OFFSET => self.reload = value,
+            TMCNT_OFFSET => self.count = value,
+            _ => {}
+        }
+    }
+
+    fn peek(&self, offset: u16) -> u16 {
+        match offset {
+            0 => self.control,
+            TMRR_OFFSET => self.reload,
+            TMCNT_OFFSET => self.count,
+            _ => 0,
+        }
+    }
+
+    fn interrupt(&mut self) -> Option<(u8, u8)> {
+        let requesting = TIMER_INTERRUPT_ENABLE | TIMER_EXPIRED;
+        if self.control & requesting == requesting {
+            Some((InterruptVector::Timer as u8, TIMER_PRIORITY))
+        } else {
+            None
+        }
+    }
+
+    fn tick(&mut self, cycles: u64) {
+        for _ in 0..cycles {
+            if self.control & TIMER_ENABLE == 0 {
+                return;
+            }
+            self.count = self.count.saturating_sub(1);
+            if self.count == 0 {
+                self.control |= TIMER_EXPIRED;
+                self.count = self.reload;
+                if self.reload == 0 {
+                    self.control &= !TIMER_ENABLE;
+                }
+            }
+        }
+    }
+}
+
+/// The machine control register. The clock runs while bit 15 is set.
+#[derive(Debug, Default)]
+pub struct MachineControl {
+    mcr: u16,
+}
+
+impl Device for MachineControl {
+    fn name(&self) -> &str {
+        "MCR"
+    }
+
+    fn read(&mut self, offset: u16) -> u16 {
+        self.peek(offset)
+    }
+
+    fn write(&mut self, offset: u16, value: u16) {
+        if offset == 0 {
+            self.mcr = value;
+        }
+    }
+
+    fn peek(&self, offset: u16) -> u16 {
+        if offset == 0 {
+            self.mcr
+        } else {
+            0
+        }
+    }
+}
+
+#[test]
+fn test_timer() {
+    let mut timer = Timer::default();
+    timer.write(2, 3);
+    timer.write(4, 2);
+    timer.write(0, TIMER_ENABLE | TIMER_INTERRUPT_ENABLE);
+    timer.tick(1);
+    assert_eq!(timer.peek(4), 1);
+    assert_eq!(timer.interrupt(), None);
+    timer.tick(1);
+    assert_eq!(timer.peek(4), 3);
+    assert_eq!(
+        timer.interrupt(),
+        Some((InterruptVector::Timer as u8, TIMER_PRIORITY))
+    );
+    // acknowledge
+    timer.write(0, TIMER_ENABLE | TIMER_INTERRUPT_ENABLE);
+    assert_eq!(timer.interrupt(), None);
+    timer.tick(3);
+    assert!(timer.interrupt().is_some());
+
+    // one-shot
+    timer.write(2, 0);
+    timer.write(4, 1);
+    timer.write(0, TIMER_ENABLE);
+    timer.tick(5);
+    assert_eq!(timer.peek(0), TIMER_EXPIRED);
+    assert_eq!(timer.peek(4), 0);
+}
@@ -1,135 +1,299 @@
-use crate::bits::{sign_extend, Opcode};
+use std::collections::{BTreeMap, HashSet};
+
+use crate::asm::is_free_label;
+use crate::bits::{decode, encode, AluOperand, ConditionFlags, Instruction, TrapCode};
+use crate::symbols::SymbolTable;
 use num_traits::FromPrimitive;
 
+/// Disassembles one line per word. Only words reached by running from the first one are
+/// shown as instructions, the rest as `.FILL`.
 pub fn disassemble_program(program: &[u16]) -> String {
+    let code = find_code(0, program, 0);
     let mut output = String::new();
-    for instr in program {
-        output.push_str(
-            disassemble_instruction(*instr)
-                .as_ref()
-                .map(|x| &**x)
-                .unwrap_or("BAD OPCODE"),
-        );
+    for (instr, &code) in program.iter().zip(&code) {
+        if code {
+            output.push_str(
+                disassemble_instruction(*instr)
+                    .as_ref()
+                    .map(|x| &**x)
+                    .unwrap_or("BAD OPCODE"),
+            );
+        } else {
+            output.push_str(&format!(".FILL x{:04X}", instr));
+        }
         output.push('\n');
     }
     output
 }
 
 pub fn disassemble_instruction(instr: u16) -> Option<String> {
-    let op = instr >> 12;
-    match Opcode::from_u16(op) {
-        Some(Opcode::ADD) => {
-            let r0: u16 = (instr >> 9) & 0x7;
-            let sr1: u16 = (instr >> 6) & 0x7;
-            let imm_flag = (instr >> 5) & 0x1;
-            if imm_flag == 1 {
-                let imm5 = sign_extend(instr & 0x1F, 5) as i16;
-                Some(format!("ADD R{}, R{}, #{}", r0, sr1, imm5))
-            } else {
-                let sr2 = instr & 0x7;
-                Some(format!("ADD R{}, R{}, R{}", r0, sr1, sr2))
+    disassemble(instr, |_| None)
+}
+
+/// Disassembles the instruction at `addr`, showing PC-relative operands as the label of
+/// their target where `symbols` has one.
+pub fn disassemble_instruction_at(instr: u16, addr: u16, symbols: &SymbolTable) -> Option<String> {
+    disassemble(instr, |offset| {
+        let target = addr.wrapping_add(1).wrapping_add(offset as u16);
+        symbols.label(target).map(str::to_owned)
+    })
+}
+
+/// Finds which words of an image at `origin` are code, by following every path execution
+/// can take from `entry`: falling through, branching, calling subroutines and returning
+/// from traps. A path ends where the next address depends on a register, as after `JMP` or
+/// `RET`, at `HALT` and at words that are no instruction.
+pub fn find_code(origin: u16, words: &[u16], entry: u16) -> Vec<bool> {
+    let mut code = vec![false; words.len()];
+    let mut pending = vec![entry];
+    while let Some(addr) = pending.pop() {
+        let index = usize::from(addr.wrapping_sub(origin));
+        if index >= words.len() || code[index] {
+            continue;
+        }
+        let instr = words[index];
+        let next = addr.wrapping_add(1);
+        let target = pc_target(instr, addr);
+        match decode(instr) {
+            Err(_) => continue,
+            Ok(Instruction::Br { nzp, .. }) => {
+                if nzp != 0b111 {
+                    pending.push(next);
+                }
+                if nzp != 0 {
+                    pending.extend(target);
+                }
+            }
+            Ok(Instruction::Jmp { .. }) | Ok(Instruction::Rti) => {}
+            // JSRR has no target we know, but returns all the same
+            Ok(Instruction::Jsr { .. }) | Ok(Instruction::Jsrr { .. }) => {
+                pending.push(next);
+                pending.extend(target);
             }
+            Ok(Instruction::Trap { vector }) => match TrapCode::from_u8(vector) {
+                Some(TrapCode::Halt) | Some(TrapCode::Exit) => {}
+                _ => pending.push(next),
+            },
+            Ok(_) => pending.push(next),
         }
-        Some(Opcode::AND) => {
-            let r0: u16 = (instr >> 9) & 0x7;
-            let sr1: u16 = (instr >> 6) & 0x7;
-            let imm_flag = (instr >> 5) & 0x1;
-            if imm_flag == 1 {
-                let imm5 = sign_extend(instr & 0x1F, 5) as i16;
-                Some(format!("AND R{}, R{}, #{}", r0, sr1, imm5))
-            } else {
-                let sr2 = instr & 0x7;
-                Some(format!("AND R{}, R{}, R{}", r0, sr1, sr2))
+        code[index] = true;
+    }
+    code
+}
+
+/// Disassembles an image into source that assembles back to the same words.
+///
+/// Execution is taken to start at the origin, and only the words `find_code` reaches from
+/// there are shown as instructions. PC-relative operands name their target: with its label
+/// in `symbols` if it has one, with a made-up `L3000` style label otherwise, or as a raw
+/// offset followed by the address in a comment when the target is outside the image. The
+/// other words are data: `.STRINGZ` for zero-terminated text, `.BLKW` for runs of zeros and
+/// `.FILL` for the rest, as are instructions the assembler would write differently.
+pub fn disassemble_source(origin: u16, words: &[u16], symbols: &SymbolTable) -> String {
+    let end = u32::from(origin) + words.len() as u32;
+    let inside = |addr: u16| addr >= origin && u32::from(addr) < end;
+    let code = find_code(origin, words, origin);
+
+    let mut labels: BTreeMap<u16, String> = BTreeMap::new();
+    for (addr, name) in symbols.iter() {
+        // the label the table shows for the address, if it can be written
+        let name = match symbols.label(addr) {
+            Some(first) if is_free_label(first) => first,
+            _ => name,
+        };
+        if inside(addr) && is_free_label(name) && !labels.contains_key(&addr) {
+            labels.insert(addr, name.to_string());
+        }
+    }
+    let mut taken: HashSet<String> = labels.values().cloned().collect();
+    let addresses = (0..).map(|index| origin.wrapping_add(index));
+    for ((addr, &instr), &code) in addresses.zip(words).zip(&code) {
+        let target = match pc_target(instr, addr) {
+            Some(target) if code && is_canonical(instr) && inside(target) => target,
+            _ => continue,
+        };
+        if labels.contains_key(&target) {
+            continue;
+        }
+        let mut name = format!("L{:04X}", target);
+        let mut suffix = 1;
+        while taken.contains(&name) {
+            name = format!("L{:04X}_{}", target, suffix);
+            suffix += 1;
+        }
+        taken.insert(name.clone());
+        labels.insert(target, name);
+    }
+    let labelled = |from: usize, to: usize| {
+        (from..to).any(|index| labels.contains_key(&origin.wrapping_add(index as u16)))
+    };
+
+    let mut lines = vec![format!("{:7} .ORIG x{:04X}", "", origin)];
+    let mut index = 0;
+    while index < words.len() {
+        let addr = origin.wrapping_add(index as u16);
+        let instr = words[index];
+        let (text, size) = match string_length(&words[index..]) {
+            _ if code[index] && is_canonical(instr) => {
+                let target = pc_target(instr, addr);
+                let text = disassemble(instr, |_| {
+                    target.and_then(|target| labels.get(&target).cloned())
+                })
+                .unwrap_or_default();
+                match target {
+                    Some(target) if !inside(target) => (format!("{} ; x{:04X}", text, target), 1),
+                    _ => (text, 1),
+                }
+            }
+            // a label inside would have nowhere to go, and neither would code
+            Some(length)
+                if !labelled(index + 1, index + length + 1)
+                    && !code[index..=index + length].contains(&true) =>
+            {
+                let text: String = words[index..index + length]
+                    .iter()
+                    .map(|&word| match word as u8 {
+                        b'"' => "\\\"".to_string(),
+                        b'\\' => "\\\\".to_string(),
+                        b'\n' => "\\n".to_string(),
+                        b'\t' => "\\t".to_string(),
+                        b'\r' => "\\r".to_string(),
+                        0x1b => "\\e".to_string(),
+                        c => (c as char).to_string(),
+                    })
+                    .collect();
+                (format!(".STRINGZ \"{}\"", text), length + 1)
+            }
+            _ => {
+                let zeros = words[index..]
+                    .iter()
+                    .zip(&code[index..])
+                    .take_while(|&(&word, &code)| word == 0 && !code)
+                    .count();
+                if zeros >= 2 && !labelled(index + 1, index + zeros) {
+                    (format!(".BLKW {}", zeros), zeros)
+                } else {
+                    (format!(".FILL x{:04X}", instr), 1)
+                }
             }
+        };
+        let label = labels.get(&addr).map_or("", String::as_str);
+        lines.push(format!("{:7} {}", label, text));
+        index += size;
+    }
+    lines.push(format!("{:7} .END", ""));
+    lines.iter().map(|line| format!("{}\n", line)).collect()
+}
+
+/// Where a PC-relative instruction at `addr` points.
+fn pc_target(instr: u16, addr: u16) -> Option<u16> {
+    let offset = match decode(instr) {
+        Ok(Instruction::Br { offset, .. })
+        | Ok(Instruction::Jsr { offset })
+        | Ok(Instruction::Ld { offset, .. })
+        | Ok(Instruction::Ldi { offset, .. })
+        | Ok(Instruction::Lea { offset, .. })
+        | Ok(Instruction::St { offset, .. })
+        | Ok(Instruction::Sti { offset, .. }) => offset,
+        _ => return None,
+    };
+    Some(addr.wrapping_add(1).wrapping_add(offset as u16))
+}
+
+/// Whether the assembler writes `instr` for its disassembly. Others have bits set that the
+/// CPU ignores, or are no instruction at all, and can only be kept as data.
+fn is_canonical(instr: u16) -> bool {
+    match decode(instr) {
+        // NOP is only ever 0, and a zero word is much more likely data anyway
+        Ok(Instruction::Br { nzp: 0, .. }) => false,
+        Ok(instruction) => encode(&instruction) == instr,
+        Err(_) => false,
+    }
+}
+
+/// The number of characters in the zero-terminated text `words` start with, if they do.
+/// Shorter runs are more likely data that happens to look like a character.
+fn string_length(words: &[u16]) -> Option<usize> {
+    let length = words
+        .iter()
+        .take_while(|&word| (0x20..=0x7E).contains(word) || [0x09, 0x0A, 0x0D, 0x1B].contains(word))
+        .count();
+    if length >= 2 && words.get(length) == Some(&0) {
+        Some(length)
+    } else {
+        None
+    }
+}
+
+/// `label` names the target of a PC-relative offset, if it can.
+fn disassemble<F>(instr: u16, label: F) -> Option<String>
+where
+    F: Fn(i16) -> Option<String>,
+{
+    let target = |offset: i16| label(offset).unwrap_or_else(|| format!("#{}", offset));
+    let operand = |operand: AluOperand| match operand {
+        AluOperand::Register(r) => format!("R{}", r),
+        AluOperand::Immediate(imm) => format!("#{}", imm),
+    };
+    let text = match decode(instr).ok()? {
+        Instruction::Add {
+            dr,
+            sr1,
+            operand: second,
+        } => {
+            format!("ADD R{}, R{}, {}", dr, sr1, operand(second))
+        }
+        Instruction::And {
+            dr,
+            sr1,
+            operand: second,
+        } => {
+            format!("AND R{}, R{}, {}", dr, sr1, operand(second))
         }
-        Some(Opcode::BR) => {
-            use crate::bits::ConditionFlags;
-            let cond_flag: u16 = (instr >> 9) & 0x7;
-            let n = if cond_flag & ConditionFlags::NEG as u16 == 1 {
+        // never taken
+        Instruction::Br { nzp: 0, .. } => "NOP".to_owned(),
+        Instruction::Br { nzp, offset } => {
+            let n = if nzp & ConditionFlags::NEG as u16 != 0 {
                 "n"
             } else {
                 ""
             };
-            let z = if cond_flag & ConditionFlags::ZRO as u16 == 1 {
+            let z = if nzp & ConditionFlags::ZRO as u16 != 0 {
                 "z"
             } else {
                 ""
             };
-            let p = if cond_flag & ConditionFlags::POS as u16 == 1 {
+            let p = if nzp & ConditionFlags::POS as u16 != 0 {
                 "p"
             } else {
                 ""
             };
-            let pc_offset = sign_extend(instr & 0x1ff, 9) as i16;
-            Some(format!("BR{}{}{} #{}", n, z, p, pc_offset))
-        }
-        Some(Opcode::JMP) => {
-            let r0 = (instr >> 6) & 0x7;
-            Some(format!("JMP R{}", r0))
-        }
-        Some(Opcode::JSR) => {
-            let long_flag = (instr >> 11) & 0x1;
-            if long_flag == 0 {
-                let base_r = (instr >> 6) & 0x7;
-                Some(format!("JSRR R{}", base_r))
-            } else {
-                let pc_offset = sign_extend(instr & 0x7ff, 11) as i16;
-                Some(format!("JSR 0x{:04X}", pc_offset))
-            }
-        }
-        Some(Opcode::LD) => {
-            let dr = (instr >> 9) & 0x7;
-            let pc_offset = sign_extend(instr & 0x1ff, 9) as i16;
-            Some(format!("LD R{}, #{}", dr, pc_offset))
-        }
-        Some(Opcode::LDI) => {
-            let dr = (instr >> 9) & 0x7;
-            let pc_offset = sign_extend(instr & 0x1ff, 9) as i16;
-
-            Some(format!("LD R{}, #{}", dr, pc_offset))
-        }
-        Some(Opcode::LDR) => {
-            let dr = (instr >> 9) & 0x7;
-            let base_r = (instr >> 6) & 0x7;
-            let offset = sign_extend(instr & 0x3f, 5) as i16;
-
-            Some(format!("LDR R{}, R{}, #{}", dr, base_r, offset))
-        }
-        Some(Opcode::LEA) => {
-            let dr = (instr >> 9) & 0x7;
-            let pc_offset = sign_extend(instr & 0x1ff, 9) as i16;
-
-            Some(format!("LEA R{}, #{}", dr, pc_offset))
-        }
-        Some(Opcode::NOT) => {
-            let dr = (instr >> 9) & 0x7;
-            let sr = (instr >> 6) & 0x7;
-
-            Some(format!("NOT R{}, R{}", dr, sr))
-        }
-        Some(Opcode::RTI) => Some("RTI".to_owned()),
-        Some(Opcode::ST) => {
-            let sr = (instr >> 9) & 0x7;
-            let pc_offset = sign_extend(instr & 0x1ff, 9) as i16;
-            Some(format!("ST R{}, #{}", sr, pc_offset))
-        }
-        Some(Opcode::STI) => {
-            let sr = (instr >> 9) & 0x7;
-            let pc_offset = sign_extend(instr & 0x1ff, 9) as i16;
-            Some(format!("STI R{}, #{}", sr, pc_offset))
-        }
-        Some(Opcode::STR) => {
-            let sr = (instr >> 9) & 0x7;
-            let base_r = (instr >> 6) & 0x7;
-            let offset = sign_extend(instr & 0x3F, 6) as i16;
-            Some(format!("STR R{}, R{}, #{}", sr, base_r, offset))
+            format!("BR{}{}{} {}", n, z, p, target(offset))
         }
-        Some(Opcode::TRAP) => {
-            let trapvect = instr & 0xff;
-            Some(format!("TRAP x{:04X}", trapvect))
-        }
-        _ => None,
-    }
+        Instruction::Jmp { base: 7 } => "RET".to_owned(),
+        Instruction::Jmp { base } => format!("JMP R{}", base),
+        Instruction::Jsr { offset } => format!("JSR {}", target(offset)),
+        Instruction::Jsrr { base } => format!("JSRR R{}", base),
+        Instruction::Ld { dr, offset } => format!("LD R{}, {}", dr, target(offset)),
+        Instruction::Ldi { dr, offset } => format!("LDI R{}, {}", dr, target(offset)),
+        Instruction::Ldr { dr, base, offset } => format!("LDR R{}, R{}, #{}", dr, base, offset),
+        Instruction::Lea { dr, offset } => format!("LEA R{}, {}", dr, target(offset)),
+        Instruction::Not { dr, sr } => format!("NOT R{}, R{}", dr, sr),
+        Instruction::Rti => "RTI".to_owned(),
+        Instruction::St { sr, offset } => format!("ST R{}, {}", sr, target(offset)),
+        Instruction::Sti { sr, offset } => format!("STI R{}, {}", sr, target(offset)),
+        Instruction::Str { sr, base, offset } => format!("STR R{}, R{}, #{}", sr, base, offset),
+        Instruction::Trap { vector } => match TrapCode::from_u8(vector) {
+            Some(TrapCode::GetC) => "GETC".to_owned(),
+            Some(TrapCode::Out) => "OUT".to_owned(),
+            Some(TrapCode::Puts) => "PUTS".to_owned(),
+            Some(TrapCode::In) => "IN".to_owned(),
+            Some(TrapCode::PutSp) => "PUTSP".to_owned(),
+            Some(TrapCode::Halt) => "HALT".to_owned(),
+            _ => format!("TRAP x{:04X}", vector),
+        },
+    };
+    Some(text)
 }
 
 #[cfg(test)]
@@ -170,3 +334,162 @@ AND R1, R1, R1
 "
     );
 }
+
+#[test]
+fn test_labels() {
+    let mut symbols = SymbolTable::new();
+    symbols.insert("LOOP", 0x3000);
+    symbols.insert("DATA", 0x3010);
+    // JSR #-1 at x3000
+    assert_eq!(
+        disassemble_instruction_at(0x4FFF, 0x3000, &symbols),
+        Some("JSR LOOP".to_owned())
+    );
+    // LEA R0, #15 at x3000
+    assert_eq!(
+        disassemble_instruction_at(0xE00F, 0x3000, &symbols),
+        Some("LEA R0, DATA".to_owned())
+    );
+    // no label at the target
+    assert_eq!(
+        disassemble_instruction_at(0xE00E, 0x3000, &symbols),
+        Some("LEA R0, #14".to_owned())
+    );
+}
+
+#[test]
+fn test_branches() {
+    assert_eq!(
+        disassemble_instruction(0b0000_100_111111110),
+        Some("BRn #-2".to_owned())
+    );
+    assert_eq!(
+        disassemble_instruction(0b0000_011_000000001),
+        Some("BRzp #1".to_owned())
+    );
+    assert_eq!(
+        disassemble_instruction(0b0000_111_000000000),
+        Some("BRnzp #0".to_owned())
+    );
+    assert_eq!(
+        disassemble_instruction(0xA201),
+        Some("LDI R1, #1".to_owned())
+    );
+    assert_eq!(disassemble_instruction(0xC1C0), Some("RET".to_owned()));
+    assert_eq!(disassemble_instruction(0xF025), Some("HALT".to_owned()));
+}
+
+#[test]
+fn test_source() {
+    let program = crate::asm::assemble(
+        "        .ORIG x3000
+START   LEA R0, HELLO
+        PUTS
+        LD R1, COUNT
+LOOP    ADD R1, R1, #-1
+        BRp LOOP
+        JSR #100
+        HALT
+COUNT   .FILL x0003
+        .BLKW 3
+HELLO   .STRINGZ \"a \\\"quoted\\\"\\n\"
+        .FILL x8001
+        .END",
+    )
+    .unwrap();
+    let mut symbols = SymbolTable::new();
+    symbols.insert("START", 0x3000);
+    symbols.insert("COUNT", 0x3007);
+    let source = disassemble_source(program.origin, &program.words, &symbols);
+    assert_eq!(
+        source,
+        "        .ORIG x3000
+START   LEA R0, L300B
+        PUTS
+        LD R1, COUNT
+L3003   ADD R1, R1, #-1
+        BRp L3003
+        JSR #100 ; x306A
+        HALT
+COUNT   .FILL x0003
+        .BLKW 3
+L300B   .STRINGZ \"a \\\"quoted\\\"\\n\"
+        .FILL x8001
+        .END
+"
+    );
+    assert_eq!(crate::asm::assemble(&source).unwrap().words, program.words);
+}
+
+#[test]
+fn test_source_round_trip() {
+    let image = include_bytes!("../res/2048.obj");
+    let words: Vec<u16> = image
+        .chunks(2)
+        .map(|pair| u16::from(pair[0]) << 8 | u16::from(pair[1]))
+        .collect();
+    let source = disassemble_source(words[0], &words[1..], &SymbolTable::new());
+    let program = crate::asm::assemble(&source).unwrap();
+    assert_eq!(program.origin, words[0]);
+    assert_eq!(program.words, &words[1..]);
+    // the stack pointer and the tables after it are data
+    assert!(source.contains("\nL3015   .FILL x4000\nL3016   .FILL x0000\nL3017   .FILL x0001\n"));
+
+    let program = crate::asm::assemble(include_str!("../res/hello_world.asm")).unwrap();
+    let source = disassemble_source(program.origin, &program.words, &program.symbol_table());
+    assert_eq!(
+        crate::asm::assemble(&source).unwrap().image(),
+        program.image()
+    );
+}
+
+#[test]
+fn test_find_code() {
+    let program = crate::asm::assemble(
+        "        .ORIG x3000
+        BRnzp SKIP
+        .FILL x1234
+SKIP    LEA R0, TABLE
+        JSR SUB
+        BRz DONE
+        JMP R0
+DONE    HALT
+SUB     RET
+TABLE   .FILL x1234
+        .FILL x5678
+        .END",
+    )
+    .unwrap();
+    assert_eq!(
+        find_code(program.origin, &program.words, program.origin),
+        vec![true, false, true, true, true, true, true, true, false, false]
+    );
+    assert_eq!(
+        disassemble_source(program.origin, &program.words, &SymbolTable::new()),
+        "        .ORIG x3000
+        BRnzp L3002
+        .FILL x1234
+L3002   LEA R0, L3008
+        JSR L3007
+        BRz L3006
+        JMP R0
+L3006   HALT
+L3007   RET
+L3008   .FILL x1234
+        .FILL x5678
+        .END
+"
+    );
+    assert_eq!(
+        disassemble_program(&[0x0E01, 0x1234, 0xF025, 0x5678]),
+        "BRnzp #1\n.FILL x1234\nHALT\n.FILL x5678\n"
+    );
+}
+
+#[test]
+fn test_disassemble_end_of_memory() {
+    assert_eq!(
+        disassemble_source(0xFFFF, &[0x0FFF], &SymbolTable::new()),
+        "        .ORIG xFFFF\nLFFFF   BRnzp LFFFF\n        .END\n"
+    );
+}
@@ -0,0 +1,362 @@
+use std::cell::RefCell;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+
+use crate::bits::{FileTrapCode, Register};
+use crate::vm::{VirtualMachine, VmError};
+
+/// Returned in R0 when a file trap fails.
+pub const FILE_ERROR: u16 = 0xFFFF;
+
+/// Modes accepted by the open trap in R1.
+pub const OPEN_READ: u16 = 0;
+pub const OPEN_WRITE: u16 = 1; // create or truncate
+pub const OPEN_APPEND: u16 = 2; // create or append
+
+/// Files the guest can reach through the file traps: only those below `root`. Paths are
+/// relative to the root, and may neither climb out of it with `..` nor follow a symlink
+/// that leads out of it.
+///
+/// The sandbox keeps the guest in, not other host processes out: something that can swap a
+/// directory inside the root for a symlink between the check and the open can still point
+/// the open outside. See `resolve`.
+#[derive(Debug)]
+pub struct Sandbox {
+    root: PathBuf,
+    handles: Vec<Option<File>>,
+}
+
+impl Sandbox {
+    pub fn new<P: AsRef<Path>>(root: P) -> io::Result<Self> {
+        Ok(Sandbox {
+            root: root.as_ref().canonicalize()?,
+            handles: Vec::new(),
+        })
+    }
+
+    /// Registers the open, read, write and close traps on `vm`, sharing this sandbox.
+    pub fn install(self, vm: &mut VirtualMachine) {
+        let sandbox = Rc::new(RefCell::new(self));
+        for &code in &[
+            FileTrapCode::Open,
+            FileTrapCode::Read,
+            FileTrapCode::Write,
+            FileTrapCode::Close,
+        ] {
+            let sandbox = sandbox.clone();
+            vm.register_trap(code as u8, move |vm| sandbox.borrow_mut().service(code, vm));
+        }
+    }
+
+    /// Host errors, and buffers the calling program may not access, are reported to the
+    /// guest through R0. Other memory errors fault the guest.
+    fn service(&mut self, code: FileTrapCode, vm: &mut VirtualMachine) -> Result<(), VmError> {
+        let (r0, r1, r2) = (
+            vm.register(Register::R0),
+            vm.register(Register::R1),
+            vm.register(Register::R2),
+        );
+        let result = match code {
+            FileTrapCode::Open => match read_string(vm, r0) {
+                Ok(name) => self.open(&name, r1),
+                Err(VmError::AccessViolation(addr)) => Err(denied(addr)),
+                Err(e) => return Err(e),
+            },
+            FileTrapCode::Read => {
+                let mut buf = vec![0; r2 as usize];
+                match check_buffer(vm, r1, r2)
+                    .and_then(|()| self.handle(r0))
+                    .and_then(|file| file.read(&mut buf))
+                {
+                    Ok(count) => {
+                        for (i, &byte) in buf[..count].iter().enumerate() {
+                            vm.write_memory(r1.wrapping_add(i as u16), u16::from(byte))?;
+                        }
+                        Ok(count as u16)
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            FileTrapCode::Write => match check_buffer(vm, r1, r2) {
+                Ok(()) => {
+                    let mut buf = Vec::with_capacity(r2 as usize);
+                    for i in 0..r2 {
+                        buf.push(vm.read_memory(r1.wrapping_add(i))? as u8);
+                    }
+                    self.handle(r0)
+                        .and_then(|file| file.write_all(&buf))
+                        .map(|()| r2)
+                }
+                Err(e) => Err(e),
+            },
+            FileTrapCode::Close => self.close(r0),
+        };
+        let r0 = result.unwrap_or_else(|e| {
+            debug!("file trap {:?} failed: {}", code, e);
+            FILE_ERROR
+        });
+        vm.set_register(Register::R0, r0);
+        Ok(())
+    }
+
+    fn open(&mut self, name: &str, mode: u16) -> io::Result<u16> {
+        let path = self.resolve(name)?;
+        let mut options = OpenOptions::new();
+        match mode {
+            OPEN_READ => options.read(true),
+            OPEN_WRITE => options.write(true).create(true).truncate(true),
+            OPEN_APPEND => options.append(true).create(true),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "bad open mode")),
+        };
+        // a symlink put in place after `resolve` looked must not be followed either
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.custom_flags(libc::O_NOFOLLOW);
+        }
+        let file = options.open(path)?;
+        let slot = match self.handles.iter().position(Option::is_none) {
+            Some(slot) => slot,
+            None => {
+                self.handles.push(None);
+                self.handles.len() - 1
+            }
+        };
+        if slot >= FILE_ERROR as usize {
+            return Err(io::Error::new(io::ErrorKind::Other, "too many open files"));
+        }
+        self.handles[slot] = Some(file);
+        Ok(slot as u16)
+    }
+
+    fn close(&mut self, handle: u16) -> io::Result<u16> {
+        self.handle(handle)?;
+        self.handles[handle as usize] = None;
+        Ok(0)
+    }
+
+    fn handle(&mut self, handle: u16) -> io::Result<&mut File> {
+        self.handles
+            .get_mut(handle as usize)
+            .and_then(Option::as_mut)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "bad file handle"))
+    }
+
+    /// Maps a guest path to a host path inside the sandbox.
+    ///
+    /// The path is checked, then opened by name, so the check holds only as long as nothing
+    /// else changes the tree in between. `open` refuses a symlink as the last component, but
+    /// a directory further up that is replaced by a symlink after the check is followed.
+    fn resolve(&self, name: &str) -> io::Result<PathBuf> {
+        let escapes = || {
+            io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{:?} is outside the sandbox", name),
+            )
+        };
+        let relative = Path::new(name);
+        if name.is_empty()
+            || !relative.components().all(|component| match component {
+                Component::Normal(_) => true,
+                _ => false,
+            })
+        {
+            return Err(escapes());
+        }
+        let path = self.root.join(relative);
+        // symlinks are resolved, the file itself may not exist yet
+        let resolved = match path.canonicalize() {
+            Ok(resolved) => resolved,
+            Err(_) => {
+                // a symlink that does not resolve points at a file that opening would create,
+                // wherever that is
+                if path.symlink_metadata().is_ok() {
+                    return Err(escapes());
+                }
+                let parent = path.parent().ok_or_else(escapes)?.canonicalize()?;
+                parent.join(path.file_name().ok_or_else(escapes)?)
+            }
+        };
+        if resolved.starts_with(&self.root) {
+            Ok(resolved)
+        } else {
+            Err(escapes())
+        }
+    }
+}
+
+/// The error for a buffer at `addr` the calling program may not access.
+fn denied(addr: u16) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        VmError::AccessViolation(addr).to_string(),
+    )
+}
+
+/// Checks that the program that called the trap may access the `length` words at `addr`,
+/// as it could with its own loads and stores.
+fn check_buffer(vm: &VirtualMachine, addr: u16, length: u16) -> io::Result<()> {
+    for i in 0..length {
+        let addr = addr.wrapping_add(i);
+        if vm.check_access(addr).is_err() {
+            return Err(denied(addr));
+        }
+    }
+    Ok(())
+}
+
+/// Reads a zero-terminated string, one character per word, from memory the calling program
+/// may access.
+fn read_string(vm: &VirtualMachine, mut addr: u16) -> Result<String, VmError> {
+    let mut string = String::new();
+    loop {
+        vm.check_access(addr)?;
+        let c = vm.read_memory(addr)? as u8;
+        if c == 0 {
+            return Ok(string);
+        }
+        string.push(char::from(c));
+        addr = addr.wrapping_add(1);
+    }
+}
+
+/// Runs the file trap `code` with the given PSR and arguments, and returns R0.
+#[cfg(test)]
+fn call_trap(
+    vm: &mut VirtualMachine,
+    psr: u16,
+    code: FileTrapCode,
+    r0: u16,
+    r1: u16,
+    r2: u16,
+) -> u16 {
+    use crate::vm::StopReason;
+
+    vm.write_memory(0x3000, 0xF000 | code as u16).unwrap();
+    vm.set_register(Register::PC, 0x3000);
+    vm.set_register(Register::PSR, psr);
+    vm.set_register(Register::R0, r0);
+    vm.set_register(Register::R1, r1);
+    vm.set_register(Register::R2, r2);
+    assert_eq!(vm.run_for(1), StopReason::InstructionLimit);
+    vm.register(Register::R0)
+}
+
+#[test]
+fn test_file_traps() {
+    let root = std::env::temp_dir().join(format!("memevm-sandbox-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(root.join("in.txt"), b"hello").unwrap();
+
+    let mut vm = VirtualMachine::with_memory(0x10000);
+    Sandbox::new(&root).unwrap().install(&mut vm);
+    let trap = |vm: &mut VirtualMachine, code, r0, r1, r2| call_trap(vm, 0, code, r0, r1, r2);
+    let write_string = |vm: &mut VirtualMachine, addr: u16, s: &str| {
+        for (i, c) in s.bytes().chain(std::iter::once(0)).enumerate() {
+            vm.write_memory(addr + i as u16, u16::from(c)).unwrap();
+        }
+    };
+
+    write_string(&mut vm, 0x4000, "in.txt");
+    let input = trap(&mut vm, FileTrapCode::Open, 0x4000, OPEN_READ, 0);
+    assert_eq!(input, 0);
+    assert_eq!(trap(&mut vm, FileTrapCode::Read, input, 0x5000, 16), 5);
+    assert_eq!(vm.read_memory(0x5000), Ok(u16::from(b'h')));
+    assert_eq!(vm.read_memory(0x5004), Ok(u16::from(b'o')));
+    assert_eq!(trap(&mut vm, FileTrapCode::Read, input, 0x5000, 16), 0);
+
+    write_string(&mut vm, 0x4000, "out.txt");
+    let output = trap(&mut vm, FileTrapCode::Open, 0x4000, OPEN_WRITE, 0);
+    assert_eq!(output, 1);
+    assert_eq!(trap(&mut vm, FileTrapCode::Write, output, 0x5000, 4), 4);
+    assert_eq!(trap(&mut vm, FileTrapCode::Close, output, 0, 0), 0);
+    assert_eq!(std::fs::read(root.join("out.txt")).unwrap(), b"hell");
+    assert_eq!(trap(&mut vm, FileTrapCode::Close, output, 0, 0), FILE_ERROR);
+    assert_eq!(
+        trap(&mut vm, FileTrapCode::Write, output, 0x5000, 4),
+        FILE_ERROR
+    );
+    assert_eq!(trap(&mut vm, FileTrapCode::Close, input, 0, 0), 0);
+
+    for name in &["../escape.txt", "/etc/passwd", "a/../../escape.txt", ""] {
+        write_string(&mut vm, 0x4000, name);
+        assert_eq!(
+            trap(&mut vm, FileTrapCode::Open, 0x4000, OPEN_WRITE, 0),
+            FILE_ERROR,
+            "{}",
+            name
+        );
+    }
+    assert!(!root.parent().unwrap().join("escape.txt").exists());
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_file_traps_respect_privilege() {
+    use crate::bits::PSR_USER_MODE;
+
+    let root = std::env::temp_dir().join(format!("memevm-privilege-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(root.join("in.txt"), b"hello").unwrap();
+
+    let mut vm = VirtualMachine::with_memory(0x10000);
+    Sandbox::new(&root).unwrap().install(&mut vm);
+    let trap =
+        |vm: &mut VirtualMachine, code, r0, r1, r2| call_trap(vm, PSR_USER_MODE, code, r0, r1, r2);
+    for (i, c) in b"in.txt\0".iter().enumerate() {
+        vm.write_memory(0x2000 + i as u16, u16::from(*c)).unwrap();
+        vm.write_memory(0x4000 + i as u16, u16::from(*c)).unwrap();
+    }
+
+    // a name in system space is as out of reach as a buffer there
+    assert_eq!(
+        trap(&mut vm, FileTrapCode::Open, 0x2000, OPEN_READ, 0),
+        FILE_ERROR
+    );
+    let input = trap(&mut vm, FileTrapCode::Open, 0x4000, OPEN_READ, 0);
+    assert_eq!(input, 0);
+    vm.write_memory(0x2FFF, 0x1234).unwrap();
+    assert_eq!(
+        trap(&mut vm, FileTrapCode::Read, input, 0x2FFF, 2),
+        FILE_ERROR
+    );
+    assert_eq!(vm.read_memory(0x2FFF), Ok(0x1234));
+    assert_eq!(
+        trap(&mut vm, FileTrapCode::Read, input, 0xFDFF, 2),
+        FILE_ERROR
+    );
+    // nothing was read by the refused calls
+    assert_eq!(trap(&mut vm, FileTrapCode::Read, input, 0x5000, 16), 5);
+    assert_eq!(
+        trap(&mut vm, FileTrapCode::Write, input, 0x0000, 1),
+        FILE_ERROR
+    );
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_symlink_escape() {
+    let base = std::env::temp_dir().join(format!("memevm-symlink-{}", std::process::id()));
+    let root = base.join("root");
+    std::fs::create_dir_all(&root).unwrap();
+    let outside = base.join("outside.txt");
+    std::os::unix::fs::symlink(&outside, root.join("link")).unwrap();
+
+    let mut vm = VirtualMachine::with_memory(0x10000);
+    Sandbox::new(&root).unwrap().install(&mut vm);
+    for (i, c) in b"link\0".iter().enumerate() {
+        vm.write_memory(0x4000 + i as u16, u16::from(*c)).unwrap();
+    }
+    assert_eq!(
+        call_trap(&mut vm, 0, FileTrapCode::Open, 0x4000, OPEN_WRITE, 0),
+        FILE_ERROR
+    );
+    assert!(!outside.exists());
+
+    std::fs::remove_dir_all(&base).unwrap();
+}
@@ -1,6 +1,6 @@
 use imgui::{im_str, ImGuiCond, Ui};
 
-use crate::bits::DiagnosticStatus;
+use crate::bits::{DiagnosticStatus, Register};
 use std::sync::{Arc, Mutex};
 use std::thread;
 
@@ -95,7 +95,17 @@ impl Scene {
                 for (register, contents) in self.latest_diagnostics.registers {
                     let register_name = format!("{:?}", register);
                     let additional_spaces = " ".repeat(register_name_max_length - register_name.len());
-                    ui.text(im_str!("{}:{}{}", register_name, additional_spaces, self.current_display_format.format(contents)));
+                    // show where PC is in the program when it is at a label
+                    let label = match register {
+                        Register::PC => self
+                            .latest_diagnostics
+                            .symbols
+                            .label(contents)
+                            .map(|label| format!(" <{}>", label))
+                            .unwrap_or_default(),
+                        _ => String::new(),
+                    };
+                    ui.text(im_str!("{}:{}{}{}", register_name, additional_spaces, self.current_display_format.format(contents), label));
                 }
                 if ui.radio_button_bool(im_str!("Hex"), self.current_display_format == DisplayFormat::Hexadecimal) {
                     self.current_display_format = DisplayFormat::Hexadecimal;
@@ -131,6 +141,12 @@ impl Scene {
                     )
                 };
                 ui.text(im_str!("len: {}", meme.len()));
+                let (start, length) = self.latest_diagnostics.memory_view_range;
+                for (addr, label) in self.latest_diagnostics.symbols.iter() {
+                    if (start..=start + length).contains(&(addr as usize)) {
+                        ui.text(im_str!("{:04X} {}", addr, label));
+                    }
+                }
                 for line in hexdump::hexdump_iter(&meme) {
                     ui.text(im_str!("{}", line));
                 }
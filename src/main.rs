@@ -3,8 +3,16 @@ extern crate log;
 #[macro_use]
 extern crate enum_map;
 
+mod asm;
 mod bits;
+mod bus;
+#[cfg(test)]
+mod conformance;
+mod console;
+mod devices;
 mod disasm;
+mod fileio;
+mod symbols;
 mod vm;
 
 #[cfg(feature = "gui")]
@@ -17,15 +25,483 @@ pub struct Environment {
     pub diagnostics_mutex: Arc<Mutex<bits::DiagnosticStatus>>,
 }
 
+// exit codes of the process when the guest did not get to choose one with EXIT. Nothing
+// stops a guest from exiting with one of these too, so a script that needs to tell them apart
+// has to look at the log, where the VM reports its own failures.
+const EXIT_ASSEMBLY_ERROR: i32 = 65;
+const EXIT_LOAD_ERROR: i32 = 66;
+const EXIT_FAULT: i32 = 70;
+const EXIT_INSTRUCTION_LIMIT: i32 = 124;
+const EXIT_WRITE_ERROR: i32 = 73;
+
+/// Where an OS image given with `MEMEVM_OS` starts, as the standard LC-3 OS does.
+const OS_ENTRY: u16 = 0x0200;
+
+/// How many instructions a run with scripted input executes between writing out the
+/// guest's output.
+const OUTPUT_SLICE: u64 = 10_000;
+
+/// Writes log records to stderr, so that they stay out of the guest's output and of what
+/// `--disassemble` prints.
+struct StderrLogger;
+
+impl log::Log for StderrLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("{:<5} [{}] {}", record.level(), record.target(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+/// Logs at info level, or at the level named by `MEMEVM_LOG`, such as `debug` or `trace`.
+fn init_logging() {
+    let level = std::env::var("MEMEVM_LOG")
+        .ok()
+        .and_then(|level| level.parse::<log::LevelFilter>().ok())
+        .unwrap_or(log::LevelFilter::Info);
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(level);
+}
+
 fn main() {
+    let status = run();
+    // the VM, and with it the terminal settings, is dropped by now
+    std::process::exit(status);
+}
+
+fn run() -> i32 {
     let env = Environment::default();
-    simple_logger::init_with_level(log::Level::Debug).unwrap();
+    init_logging();
     #[cfg(feature = "gui")]
     gui::run(env.diagnostics_mutex.clone());
     //curses_ui::start(env.diagnostics_mutex.clone());
-    let mut vm = vm::VirtualMachine::with_memory(u16::max_value() as usize);
-    vm.read_image_file(&mut ::std::fs::File::open("./res/2048.obj").unwrap());
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    // `--disassemble IMAGE` prints the image as source instead of running it
+    if args.first().map(String::as_str) == Some("--disassemble") {
+        return match args.get(1) {
+            Some(path) => print_source(std::path::Path::new(path)),
+            None => {
+                error!("--disassemble expects an image");
+                EXIT_LOAD_ERROR
+            }
+        };
+    }
+    // `--assemble SOURCE [IMAGE]` writes the image, its symbols and listing instead. Like
+    // `--object`, it reads the source from stdin if SOURCE is `-`
+    if args.first().map(String::as_str) == Some("--assemble") {
+        return match args.get(1) {
+            Some(source) => write_program(
+                std::path::Path::new(source),
+                args.get(2).map(std::path::Path::new),
+            ),
+            None => {
+                error!("--assemble expects a source file");
+                EXIT_LOAD_ERROR
+            }
+        };
+    }
+    // `--object SOURCE [OBJECT]` assembles a module to be linked with others
+    if args.first().map(String::as_str) == Some("--object") {
+        return match args.get(1) {
+            Some(source) => write_object(
+                std::path::Path::new(source),
+                args.get(2).map(std::path::Path::new),
+            ),
+            None => {
+                error!("--object expects a source file");
+                EXIT_LOAD_ERROR
+            }
+        };
+    }
+    // `--link IMAGE OBJECT[@ORIGIN]...` joins objects into an image and its symbols
+    if args.first().map(String::as_str) == Some("--link") {
+        if args.len() < 3 {
+            error!("--link expects an image and the objects to link into it");
+            return EXIT_LOAD_ERROR;
+        }
+        return link(std::path::Path::new(&args[1]), &args[2..]);
+    }
+    let mut vm = vm::VirtualMachine::with_memory(u16::max_value() as usize + 1);
+    let path = std::path::Path::new(args.first().map_or("./res/2048.obj", String::as_str));
+    if let Err(status) = load(&mut vm, path) {
+        return status;
+    }
+    // guest file I/O is off unless a sandbox directory is given
+    if let Some(root) = std::env::var_os("MEMEVM_SANDBOX") {
+        match fileio::Sandbox::new(&root) {
+            Ok(sandbox) => sandbox.install(&mut vm),
+            Err(e) => {
+                error!("could not open sandbox {:?}: {}", root, e);
+                return EXIT_LOAD_ERROR;
+            }
+        }
+    }
+    // keys come from a file instead of the terminal if one is given, and the output is
+    // collected and written out every so often
+    let script = match std::env::var_os("MEMEVM_INPUT") {
+        Some(path) => match std::fs::read(&path) {
+            Ok(keys) => {
+                let console = console::BufferConsole::with_input(&keys);
+                console.close_input();
+                vm.set_console(Box::new(console.clone()));
+                Some(console)
+            }
+            Err(e) => {
+                error!("could not read input {:?}: {}", path, e);
+                return EXIT_LOAD_ERROR;
+            }
+        },
+        None => None,
+    };
+    // with an OS, traps go through its vector table and it starts the program itself
+    if let Some(os) = std::env::var_os("MEMEVM_OS") {
+        let booted = std::fs::read(&os)
+            .map_err(|e| e.to_string())
+            .and_then(|image| vm.boot_os(&image, OS_ENTRY).map_err(|e| e.to_string()));
+        if let Err(e) = booted {
+            error!("{:?}: {}", os, e);
+            return EXIT_LOAD_ERROR;
+        }
+        // the built-in routines stay available as a faster stand-in for the OS's own
+        if std::env::var("MEMEVM_TRAPS")
+            .ok()
+            .as_ref()
+            .map(String::as_str)
+            == Some("native")
+        {
+            vm.set_trap_mode(vm::TrapMode::Native);
+        }
+    }
+    // each address or label in `MEMEVM_BREAK` reports the machine the first time it is reached
+    if let Ok(breakpoints) = std::env::var("MEMEVM_BREAK") {
+        for breakpoint in breakpoints.split(',').map(str::trim) {
+            match vm
+                .symbols()
+                .address(breakpoint)
+                .or_else(|| parse_address(breakpoint))
+            {
+                Some(addr) => vm.add_breakpoint(addr),
+                None => {
+                    error!("no label or address {:?} to break at", breakpoint);
+                    return EXIT_LOAD_ERROR;
+                }
+            }
+        }
+    }
+    let limit = std::env::var("MEMEVM_INSTRUCTION_LIMIT")
+        .ok()
+        .and_then(|limit| limit.parse::<u64>().ok());
     vm.add_diagnostic_mutex(env.diagnostics_mutex.clone());
-    //vm.memory_dump();
-    vm.run();
+    loop {
+        let mut budget = limit.map(|limit| limit.saturating_sub(vm.cycles()));
+        if script.is_some() {
+            budget = Some(budget.map_or(OUTPUT_SLICE, |budget| budget.min(OUTPUT_SLICE)));
+        }
+        let reason = match budget {
+            Some(budget) => vm.run_for(budget),
+            None => vm.run(),
+        };
+        if let Some(console) = &script {
+            console::write_stdout(&console.take_output());
+        }
+        match reason {
+            vm::StopReason::Halted => return exit_code(vm.exit_status()),
+            vm::StopReason::Fault(fault) => {
+                error!("{}", fault);
+                if let vm::VmError::AccessViolation(_) = fault.error {
+                    for (range, device) in vm.bus().memory_map() {
+                        info!("x{:04X}-x{:04X}: {}", range.start(), range.end(), device);
+                    }
+                }
+                return EXIT_FAULT;
+            }
+            vm::StopReason::InstructionLimit if limit.map_or(true, |limit| vm.cycles() < limit) => {
+            }
+            vm::StopReason::InstructionLimit => {
+                error!("instruction limit of {} reached", vm.cycles());
+                return EXIT_INSTRUCTION_LIMIT;
+            }
+            vm::StopReason::WaitingForInput => {
+                std::thread::sleep(std::time::Duration::from_millis(10))
+            }
+            vm::StopReason::Breakpoint => {
+                let pc = vm.register(bits::Register::PC);
+                report_breakpoint(&vm);
+                vm.remove_breakpoint(pc);
+            }
+        }
+    }
+}
+
+/// Prints the registers and the next few instructions to stderr.
+fn report_breakpoint(vm: &vm::VirtualMachine) {
+    use bits::Register;
+    let pc = vm.register(Register::PC);
+    eprintln!("breakpoint at x{:04X}", pc);
+    let registers = vec![
+        Register::R0,
+        Register::R1,
+        Register::R2,
+        Register::R3,
+        Register::R4,
+        Register::R5,
+        Register::R6,
+        Register::R7,
+        Register::PSR,
+    ];
+    let registers: Vec<String> = registers
+        .into_iter()
+        .map(|register| {
+            let name = format!("{:?}", register);
+            format!("{}: x{:04X}", name, vm.register(register))
+        })
+        .collect();
+    eprintln!("{}", registers.join("  "));
+    eprint!(
+        "{}",
+        vm.disassemble_region(usize::from(pc), usize::from(3.min(0xFFFF - pc)), true)
+    );
+}
+
+/// Parses an address written as in assembly, `x3000`, or as plain hex.
+fn parse_address(text: &str) -> Option<u16> {
+    let digits = text.trim_start_matches(|c| c == 'x' || c == 'X');
+    u16::from_str_radix(digits, 16).ok()
+}
+
+/// The process exit code for the status the guest exited with. Only the low 8 bits reach
+/// the parent, so a failure that would read as success there is reported as 1 instead.
+fn exit_code(status: u16) -> i32 {
+    match status as u8 {
+        0 if status != 0 => 1,
+        code => i32::from(code),
+    }
+}
+
+/// Loads the program at `path` with its symbols. Assembly sources are assembled in memory.
+fn load(vm: &mut vm::VirtualMachine, path: &std::path::Path) -> Result<(), i32> {
+    if path
+        .extension()
+        .map_or(false, |extension| extension == "asm")
+    {
+        let program = match asm::assemble_file(path) {
+            Ok(program) => program,
+            Err(diagnostics) => {
+                print_diagnostics(&diagnostics);
+                return Err(EXIT_ASSEMBLY_ERROR);
+            }
+        };
+        if let Err(e) = vm.read_image(&program.image()) {
+            error!("{}", e);
+            return Err(EXIT_LOAD_ERROR);
+        }
+        vm.set_symbols(program.symbol_table());
+        return Ok(());
+    }
+
+    let mut file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) => {
+            error!("{:?}: {}", path, e);
+            return Err(EXIT_LOAD_ERROR);
+        }
+    };
+    if let Err(e) = vm.read_image_file(&mut file) {
+        error!("{:?}: {}", path, e);
+        return Err(EXIT_LOAD_ERROR);
+    }
+    match symbols::SymbolTable::load_beside(path) {
+        Ok(Some(symbols)) => vm.set_symbols(symbols),
+        Ok(None) => {}
+        Err(e) => warn!("could not load symbols: {}", e),
+    }
+    Ok(())
+}
+
+/// Assembles `source` into an image at `output`, by default beside it with the `.obj`
+/// extension, and writes the symbol table and listing beside the image.
+fn write_program(source: &std::path::Path, output: Option<&std::path::Path>) -> i32 {
+    let output = match output_path(source, output, "obj") {
+        Ok(output) => output,
+        Err(status) => return status,
+    };
+    let assembled = if source == std::path::Path::new("-") {
+        match read_stdin() {
+            Ok(text) => asm::assemble(&text),
+            Err(status) => return status,
+        }
+    } else {
+        asm::assemble_file(source)
+    };
+    let program = match assembled {
+        Ok(program) => program,
+        Err(diagnostics) => {
+            print_diagnostics(&diagnostics);
+            return EXIT_ASSEMBLY_ERROR;
+        }
+    };
+    if let Err(e) = program.write_files(&output) {
+        error!("{:?}: {}", output, e);
+        return EXIT_WRITE_ERROR;
+    }
+    0
+}
+
+/// Assembles `source` into a relocatable object at `output`, by default beside it with the
+/// `.o` extension.
+fn write_object(source: &std::path::Path, output: Option<&std::path::Path>) -> i32 {
+    let output = match output_path(source, output, "o") {
+        Ok(output) => output,
+        Err(status) => return status,
+    };
+    let assembled = if source == std::path::Path::new("-") {
+        match read_stdin() {
+            Ok(text) => asm::assemble_object(&text),
+            Err(status) => return status,
+        }
+    } else {
+        asm::assemble_object_file(source)
+    };
+    let object = match assembled {
+        Ok(object) => object,
+        Err(diagnostics) => {
+            print_diagnostics(&diagnostics);
+            return EXIT_ASSEMBLY_ERROR;
+        }
+    };
+    if let Err(e) = object.write_file(&output) {
+        error!("{:?}: {}", output, e);
+        return EXIT_WRITE_ERROR;
+    }
+    0
+}
+
+/// Where to write what `source` assembles to: `output` if there is one, otherwise beside
+/// the source with `extension`. Source from stdin has to be given an output.
+fn output_path(
+    source: &std::path::Path,
+    output: Option<&std::path::Path>,
+    extension: &str,
+) -> Result<std::path::PathBuf, i32> {
+    match output {
+        Some(output) => Ok(output.to_path_buf()),
+        None if source == std::path::Path::new("-") => {
+            error!("source from stdin needs a file to write to");
+            Err(EXIT_LOAD_ERROR)
+        }
+        None => Ok(source.with_extension(extension)),
+    }
+}
+
+/// Reads assembly source from stdin. Its includes are looked up in the working directory.
+fn read_stdin() -> Result<String, i32> {
+    use std::io::Read;
+    let mut text = String::new();
+    match std::io::stdin().read_to_string(&mut text) {
+        Ok(_) => Ok(text),
+        Err(e) => {
+            error!("stdin: {}", e);
+            Err(EXIT_LOAD_ERROR)
+        }
+    }
+}
+
+/// Links the object files in `modules` into an image at `output`, with its symbol table
+/// beside it. A module is placed where it was assembled, or at the hex address after an
+/// `@`, as in `lib.o@x4000`.
+fn link(output: &std::path::Path, modules: &[String]) -> i32 {
+    let mut linker = asm::Linker::new();
+    for module in modules {
+        let (path, origin) = match module.rfind('@') {
+            Some(at) => match parse_address(&module[at + 1..]) {
+                Some(origin) => (&module[..at], Some(origin)),
+                None => {
+                    error!("{}: bad origin", module);
+                    return EXIT_LOAD_ERROR;
+                }
+            },
+            None => (module.as_str(), None),
+        };
+        match asm::Object::load(path) {
+            Ok(object) => linker.add(path, object, origin),
+            Err(e) => {
+                error!("{:?}: {}", path, e);
+                return EXIT_LOAD_ERROR;
+            }
+        }
+    }
+    let program = match linker.link() {
+        Ok(program) => program,
+        Err(e) => {
+            error!("{}", e);
+            return EXIT_ASSEMBLY_ERROR;
+        }
+    };
+    if let Err(e) = program.write_files(output) {
+        error!("{:?}: {}", output, e);
+        return EXIT_WRITE_ERROR;
+    }
+    0
+}
+
+/// Prints assembler errors to stderr, as JSON if `MEMEVM_DIAGNOSTICS` is `json`.
+fn print_diagnostics(diagnostics: &asm::Diagnostics) {
+    if std::env::var("MEMEVM_DIAGNOSTICS")
+        .ok()
+        .as_ref()
+        .map(String::as_str)
+        == Some("json")
+    {
+        eprintln!("{}", diagnostics.to_json());
+    } else {
+        eprint!("{}", diagnostics);
+    }
+}
+
+/// Prints the image at `path` as assembly source, with the labels of the symbol file beside it.
+fn print_source(path: &std::path::Path) -> i32 {
+    let image = match std::fs::read(path) {
+        Ok(image) => image,
+        Err(e) => {
+            error!("{:?}: {}", path, e);
+            return EXIT_LOAD_ERROR;
+        }
+    };
+    if image.len() < 2 || image.len() % 2 != 0 {
+        error!("{:?}: not a whole number of words", path);
+        return EXIT_LOAD_ERROR;
+    }
+    let words: Vec<u16> = image
+        .chunks(2)
+        .map(|pair| u16::from(pair[0]) << 8 | u16::from(pair[1]))
+        .collect();
+    let symbols = match symbols::SymbolTable::load_beside(path) {
+        Ok(symbols) => symbols.unwrap_or_default(),
+        Err(e) => {
+            warn!("could not load symbols: {}", e);
+            symbols::SymbolTable::new()
+        }
+    };
+    print!(
+        "{}",
+        disasm::disassemble_source(words[0], &words[1..], &symbols)
+    );
+    0
+}
+
+#[test]
+fn test_exit_code() {
+    assert_eq!(exit_code(0), 0);
+    assert_eq!(exit_code(3), 3);
+    assert_eq!(exit_code(0x0103), 3);
+    assert_eq!(exit_code(0x0100), 1);
+    assert_eq!(exit_code(0xFF00), 1);
 }
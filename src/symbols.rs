@@ -0,0 +1,136 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io;
+use std::path::Path;
+
+/// Labels and their addresses, as found in `.sym` files. These are in the format written by
+/// lc3as, where every symbol is on a `//` comment line after a header.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SymbolTable {
+    addresses: BTreeMap<String, u16>,
+    labels: BTreeMap<u16, String>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a symbol, or moves it if the name is taken. Where several labels share an
+    /// address, the first one is shown.
+    pub fn insert(&mut self, name: &str, addr: u16) {
+        if let Some(old) = self.addresses.insert(name.to_string(), addr) {
+            if old != addr && self.label(old) == Some(name) {
+                self.labels.remove(&old);
+                // another label left at the old address is shown instead
+                let other = self.addresses.iter().find(|&(_, &other)| other == old);
+                if let Some((other, _)) = other {
+                    self.labels.insert(old, other.clone());
+                }
+            }
+        }
+        self.labels.entry(addr).or_insert_with(|| name.to_string());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.addresses.is_empty()
+    }
+
+    pub fn address(&self, name: &str) -> Option<u16> {
+        self.addresses.get(name).cloned()
+    }
+
+    pub fn label(&self, addr: u16) -> Option<&str> {
+        self.labels.get(&addr).map(String::as_str)
+    }
+
+    /// Symbols ordered by address.
+    pub fn iter(&self) -> impl Iterator<Item = (u16, &str)> {
+        let mut symbols: Vec<_> = self
+            .addresses
+            .iter()
+            .map(|(name, &addr)| (addr, name.as_str()))
+            .collect();
+        symbols.sort();
+        symbols.into_iter()
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut table = SymbolTable::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            if !line.starts_with("//") {
+                return Err(format!("line {}: expected a `//` comment", index + 1));
+            }
+            // the header lines are comments too, only `name address` pairs count
+            let fields: Vec<&str> = line[2..].split_whitespace().collect();
+            if let [name, addr] = fields.as_slice() {
+                if let Ok(addr) = u16::from_str_radix(addr, 16) {
+                    table.insert(name, addr);
+                }
+            }
+        }
+        Ok(table)
+    }
+
+    /// Loads the symbols in the `.sym` file next to `image`, if there is one.
+    pub fn load_beside<P: AsRef<Path>>(image: P) -> io::Result<Option<Self>> {
+        let path = image.as_ref().with_extension("sym");
+        if !path.exists() {
+            return Ok(None);
+        }
+        let text = std::fs::read_to_string(&path)?;
+        Self::parse(&text)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}: {}", path, e)))
+    }
+
+    /// The format of lc3as, which other LC-3 tools read too.
+    pub fn to_lc3as(&self) -> String {
+        let mut text = String::from(
+            "// Symbol table\n\
+             // Scope level 0:\n\
+             //\tSymbol Name       Page Address\n\
+             //\t----------------  ------------\n",
+        );
+        for (addr, name) in self.iter() {
+            let _ = writeln!(text, "//\t{:<16}  {:04X}", name, addr);
+        }
+        text.push('\n');
+        text
+    }
+}
+
+#[test]
+fn test_symbol_formats() {
+    let mut table = SymbolTable::new();
+    table.insert("LOOP", 0x3001);
+    table.insert("HELLO_STR", 0x3003);
+    table.insert("START", 0x3000);
+    assert_eq!(table.label(0x3003), Some("HELLO_STR"));
+    assert_eq!(table.address("LOOP"), Some(0x3001));
+
+    let lc3as = table.to_lc3as();
+    assert!(lc3as.contains("//\tHELLO_STR         3003\n"));
+    assert_eq!(SymbolTable::parse(&lc3as), Ok(table));
+    assert!(SymbolTable::parse("x3000 START").is_err());
+}
+
+#[test]
+fn test_symbol_moved() {
+    let mut table = SymbolTable::new();
+    table.insert("LOOP", 0x3001);
+    table.insert("AGAIN", 0x3001);
+    table.insert("START", 0x3000);
+    table.insert("START", 0x3002);
+    assert_eq!(table.label(0x3000), None);
+    assert_eq!(table.label(0x3002), Some("START"));
+
+    table.insert("LOOP", 0x3005);
+    assert_eq!(table.label(0x3001), Some("AGAIN"));
+    assert_eq!(table.label(0x3005), Some("LOOP"));
+    assert_eq!(table.address("LOOP"), Some(0x3005));
+}
@@ -4,47 +4,286 @@ use num_traits::FromPrimitive;
 
 use byteorder::{BigEndian, ReadBytesExt};
 
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::rc::Rc;
 use std::sync::{Arc, Mutex};
 
+use crate::bus::{Bus, Ram};
+use crate::console::{Console, SharedConsole, TerminalConsole};
+use crate::devices::{Display, Keyboard, MachineControl, Timer};
+use crate::disasm::{disassemble_instruction_at, disassemble_program};
+use crate::symbols::SymbolTable;
+
 use crate::bits::{
-    sign_extend, ConditionFlags, DiagnosticStatus, MemoryMappedRegister, Opcode, Register, TrapCode,
+    decode, AluOperand, ConditionFlags, DecodeError, DiagnosticStatus, ExceptionVector,
+    Instruction, MemoryMappedRegister, Register, TrapCode, EXCEPTION_VECTOR_TABLE,
+    INTERRUPT_VECTOR_TABLE, MCR_CLOCK_ENABLE, PSR_CONDITION_MASK, PSR_PRIORITY_MASK, PSR_USER_MODE,
+    TRAP_VECTOR_TABLE, USER_SPACE,
 };
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VmError {
+    /// The reserved opcode (RES, 1101) was executed.
+    ReservedOpcode,
+    /// TRAP was executed with a vector that has no handler.
+    UnknownTrap(u8),
+    /// A privileged instruction was executed in user mode.
+    PrivilegeViolation,
+    /// An access to an address that is outside of memory or not permitted.
+    AccessViolation(u16),
+    /// The guest asked for input after the input stream ended.
+    InputEof,
+    /// An image could not be loaded.
+    LoadError(String),
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmError::ReservedOpcode => write!(f, "reserved opcode"),
+            VmError::UnknownTrap(vector) => write!(f, "unknown trap vector x{:02X}", vector),
+            VmError::PrivilegeViolation => write!(f, "privilege mode violation"),
+            VmError::AccessViolation(addr) => write!(f, "access violation at x{:04X}", addr),
+            VmError::InputEof => write!(f, "end of input"),
+            VmError::LoadError(reason) => write!(f, "could not load image: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for VmError {}
+
+/// A `VmError` raised while executing the instruction `instr` located at `pc`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fault {
+    pub error: VmError,
+    pub pc: u16,
+    pub instr: u16,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} (PC: x{:04X} INSTR: x{:04X})",
+            self.error, self.pc, self.instr
+        )
+    }
+}
+
+/// Why an execution call (`run_for`, `run_until`, `run`) returned control to the caller.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StopReason {
+    /// The guest stopped the clock by clearing MCR[15], which is what HALT does.
+    Halted,
+    /// PC reached a breakpoint, or the `run_until` predicate matched.
+    Breakpoint,
+    /// The instruction at `Fault::pc` could not be executed. PC is left pointing at it.
+    Fault(Fault),
+    /// The requested number of instructions has been executed.
+    InstructionLimit,
+    /// The guest needs a key that is not available yet. PC still points at the
+    /// instruction that asked for it, so it is retried on the next call.
+    WaitingForInput,
+}
+
+/// How TRAP instructions are carried out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrapMode {
+    /// GETC, OUT, PUTS, IN, PUTSP, HALT and EXIT are implemented in Rust. Other vectors go
+    /// through the trap vector table.
+    Native,
+    /// Every vector goes through the trap vector table at x0000, as on the hardware, so the
+    /// service routines of the loaded OS run.
+    Guest,
+}
+
+/// A trap service routine implemented by the embedder. It gets the whole machine, and
+/// returns to the instruction after the TRAP once it is done.
+pub type TrapHandler = Box<dyn FnMut(&mut VirtualMachine) -> Result<(), VmError>>;
+
+#[derive(Default)]
+struct TrapHandlers(HashMap<u8, TrapHandler>);
+
+impl fmt::Debug for TrapHandlers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.0.keys()).finish()
+    }
+}
+
 #[derive(Debug)]
 pub struct VirtualMachine {
-    memory: Box<[u16]>,
+    bus: Bus,
     registers: EnumMap<Register, u16>,
-    running: bool,
+    /// Clock cycles elapsed since the machine was created, one per instruction.
+    cycles: u64,
+    exit_status: u16,
+    trap_mode: TrapMode,
+    waiting_for_input: bool,
+    console: SharedConsole,
+    breakpoints: HashSet<u16>,
+    trap_handlers: TrapHandlers,
+    symbols: SymbolTable,
     diagnostic_mutex: Option<Arc<Mutex<DiagnosticStatus>>>,
 }
 
 impl VirtualMachine {
+    /// Creates a machine with `amount` words of RAM starting at x0000, and the keyboard,
+    /// display, timer and machine control registers mapped over it.
     pub fn with_memory(amount: usize) -> Self {
+        assert!(amount <= 0x10000, "more memory than the address space");
+        let console: SharedConsole = Rc::new(RefCell::new(Box::new(TerminalConsole::new())));
+        let mut bus = Bus::new();
+        if amount > 0 {
+            bus.map(0..=(amount - 1) as u16, Box::new(Ram::with_size(amount)));
+        }
+        let kbsr = MemoryMappedRegister::KBSR as u16;
+        bus.map(kbsr..=kbsr + 3, Box::new(Keyboard::new(console.clone())));
+        let dsr = MemoryMappedRegister::DSR as u16;
+        bus.map(dsr..=dsr + 3, Box::new(Display::new(console.clone())));
+        let tmcr = MemoryMappedRegister::TMCR as u16;
+        bus.map(tmcr..=tmcr + 5, Box::new(Timer::default()));
+        let mcr = MemoryMappedRegister::MCR as u16;
+        bus.map(mcr..=mcr, Box::new(MachineControl::default()));
         VirtualMachine {
-            memory: vec![0; amount].into_boxed_slice(),
+            bus,
             registers: enum_map! {
+                Register::PC => 0x3000,
+                Register::SSP => 0x3000,
+                Register::USP => 0xFE00,
                 _ => 0,
             },
-            running: false,
+            cycles: 0,
+            exit_status: 0,
+            trap_mode: TrapMode::Native,
+            waiting_for_input: false,
+            console,
+            breakpoints: HashSet::new(),
+            trap_handlers: TrapHandlers::default(),
+            symbols: SymbolTable::new(),
             diagnostic_mutex: None,
         }
     }
 
+    /// Replaces the console used for guest I/O, by default the process's stdin and stdout.
+    pub fn set_console(&mut self, console: Box<dyn Console>) {
+        *self.console.borrow_mut() = console;
+    }
+
+    pub fn bus(&self) -> &Bus {
+        &self.bus
+    }
+
+    /// Reads memory without access checks or device side effects.
+    pub fn read_memory(&self, addr: u16) -> Result<u16, VmError> {
+        self.bus.peek(addr)
+    }
+
+    /// Writes memory without access checks.
+    pub fn write_memory(&mut self, addr: u16, value: u16) -> Result<(), VmError> {
+        self.bus.write(addr, value)
+    }
+
+    pub fn set_trap_mode(&mut self, trap_mode: TrapMode) {
+        self.trap_mode = trap_mode;
+    }
+
+    /// Services `vector` with `handler` from now on, whatever the trap mode. Replaces any
+    /// handler previously registered for it.
+    pub fn register_trap<F>(&mut self, vector: u8, handler: F)
+    where
+        F: FnMut(&mut VirtualMachine) -> Result<(), VmError> + 'static,
+    {
+        self.trap_handlers.0.insert(vector, Box::new(handler));
+    }
+
+    /// Stops the clock, as if the guest had halted.
+    pub fn halt(&mut self) {
+        self.set_clock_enable(false);
+    }
+
+    /// Stops the clock with an exit status, as the EXIT trap does.
+    pub fn exit(&mut self, status: u16) {
+        self.exit_status = status;
+        self.halt();
+    }
+
+    /// The status the guest exited with, 0 unless it used EXIT.
+    pub fn exit_status(&self) -> u16 {
+        self.exit_status
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn register(&self, reg: Register) -> u16 {
+        self.registers[reg]
+    }
+
+    pub fn set_register(&mut self, reg: Register, value: u16) {
+        self.registers[reg] = value;
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.insert(addr);
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u16) -> bool {
+        self.breakpoints.remove(&addr)
+    }
+
     pub fn add_diagnostic_mutex(&mut self, sender: Arc<Mutex<DiagnosticStatus>>) {
+        if let Ok(mut diagnostics) = sender.lock() {
+            diagnostics.symbols = self.symbols.clone();
+        }
         self.diagnostic_mutex = Some(sender);
     }
 
-    pub fn read_image(&mut self, image: &[u8]) {
+    /// Labels for the loaded program, shown by the disassembler, the GUI and traces.
+    pub fn set_symbols(&mut self, symbols: SymbolTable) {
+        if let Some(arc) = &self.diagnostic_mutex {
+            if let Ok(mut diagnostics) = arc.lock() {
+                diagnostics.symbols = symbols.clone();
+            }
+        }
+        self.symbols = symbols;
+    }
+
+    pub fn symbols(&self) -> &SymbolTable {
+        &self.symbols
+    }
+
+    pub fn read_image(&mut self, image: &[u8]) -> Result<(), VmError> {
         use byteorder::ByteOrder;
+        if image.len() < 2 || image.len() % 2 != 0 {
+            return Err(VmError::LoadError(format!(
+                "image length of {} bytes is not a whole number of words",
+                image.len()
+            )));
+        }
+        // a new program has not exited yet
+        self.exit_status = 0;
         let origin = BigEndian::read_u16(&image[0..=1]);
         debug!("ORIGIN: {:x}", origin);
+        let words = image.len() / 2 - 1;
+        if origin as usize + words > 0x10000 {
+            return Err(VmError::LoadError(format!(
+                "{} words at x{:04X} do not fit in the address space",
+                words, origin
+            )));
+        }
         for (offset, word) in image
             .chunks(2)
             .skip(1)
             .filter_map(|mut x| x.read_u16::<BigEndian>().ok())
             .enumerate()
         {
-            self.mem_write(origin + offset as u16, word);
+            let addr = origin + offset as u16;
+            self.bus
+                .write(addr, word)
+                .map_err(|_| VmError::LoadError(format!("no memory at x{:04X}", addr)))?;
         }
         //self.disassemble_region(origin as usize, image.len(), true);
         /*let meme = &self.memory[origin as usize..(origin as usize+image.len())];
@@ -54,376 +293,578 @@ impl VirtualMachine {
                 meme.len() * std::mem::size_of::<u16>()
             )
         });*/
+        Ok(())
+    }
+
+    /// Copies a region of memory, reading unmapped addresses as 0.
+    fn memory_region(&self, start: usize, length: usize) -> Vec<u16> {
+        (start..=start + length)
+            .map(|addr| self.bus.peek(addr as u16).unwrap_or(0))
+            .collect()
     }
 
-    pub fn disassemble_region(&self, start: usize, length: usize, print_address: bool) {
+    /// Disassembles the words from `start` to `start + length`, one per line.
+    pub fn disassemble_region(&self, start: usize, length: usize, print_address: bool) -> String {
+        use std::fmt::Write;
+        let mut text = String::new();
         if print_address {
-            for (address, instr) in self.memory[start..=start + length]
+            for (address, instr) in self
+                .memory_region(start, length)
                 .iter()
                 .enumerate()
                 .map(|(x, y)| (x + start, y))
             {
-                println!(
+                if let Some(label) = self.symbols.label(address as u16) {
+                    let _ = writeln!(text, "{}:", label);
+                }
+                let _ = writeln!(
+                    text,
                     "{:04X} | {:016b} | {}",
                     address,
                     instr,
-                    crate::disasm::disassemble_instruction(*instr)
+                    disassemble_instruction_at(*instr, address as u16, &self.symbols)
                         .as_ref()
                         .map(|x| &**x)
                         .unwrap_or("BAD OPCODE")
-                )
+                );
             }
         } else {
-            println!(
-                "{}",
-                crate::disasm::disassemble_program(&self.memory[start..=start + length])
-            );
+            text.push_str(&disassemble_program(&self.memory_region(start, length)));
         }
+        text
     }
 
-    pub fn memory_dump(&self) {
-        hexdump::hexdump(unsafe {
-            std::slice::from_raw_parts(
-                self.memory.as_ptr() as *const u8,
-                self.memory.len() * std::mem::size_of::<u16>(),
-            )
-        });
+    /// Loads an operating system image and prepares to start it in supervisor mode at
+    /// `entry`, with traps serviced by its own routines. User programs can be loaded
+    /// afterwards with `read_image`; starting them is up to the OS.
+    pub fn boot_os(&mut self, image: &[u8], entry: u16) -> Result<(), VmError> {
+        self.read_image(image)?;
+        self.trap_mode = TrapMode::Guest;
+        self.registers[Register::PSR] = ConditionFlags::ZRO as u16;
+        self.registers[Register::PC] = entry;
+        Ok(())
     }
 
-    pub fn read_image_file(&mut self, image_file: &mut std::fs::File) {
+    pub fn read_image_file(&mut self, image_file: &mut std::fs::File) -> Result<(), VmError> {
         use std::io::Read;
         let mut buf = Vec::new();
-        image_file.read_to_end(&mut buf).unwrap();
-        self.read_image(&buf);
+        image_file
+            .read_to_end(&mut buf)
+            .map_err(|e| VmError::LoadError(e.to_string()))?;
+        self.read_image(&buf)
     }
 
     fn send_diagnostics(&self) {
-        use std::iter::FromIterator;
         if let Some(arc) = &self.diagnostic_mutex {
             if let Ok(ref mut mutex) = arc.try_lock() {
                 let requested_memory_range = mutex.memory_view_range;
                 let (range_start, range_length) = requested_memory_range;
                 mutex.registers = self.registers;
-                mutex.memory_view = Vec::from_iter(
-                    self.memory[range_start..=range_start + range_length]
-                        .iter()
-                        .cloned(),
-                );
+                mutex.memory_view = self.memory_region(range_start, range_length);
             }
         };
     }
 
-    pub fn run(&mut self) {
-        self.running = true;
-        self.registers[Register::PC] = 0x3000;
-        while self.running {
-            self.send_diagnostics();
-            let instr = self.mem_read(self.registers[Register::PC]);
-            self.registers[Register::PC] += 1;
-            let op = instr >> 12;
-            trace!(
-                "PC: {:x} INSTR: {:016b} OP: {:04b}",
-                self.registers[Register::PC] - 1,
-                instr,
-                op
-            );
-            //::std::thread::sleep(::std::time::Duration::from_millis(500));
-            match Opcode::from_u16(op) {
-                Some(Opcode::ADD) => self.op_add(instr),
-                Some(Opcode::AND) => self.op_and(instr),
-                Some(Opcode::BR) => self.op_br(instr),
-                Some(Opcode::JMP) => self.op_jmp(instr),
-                Some(Opcode::JSR) => self.op_jsr(instr),
-                Some(Opcode::LD) => self.op_ld(instr),
-                Some(Opcode::LDI) => self.op_ldi(instr),
-                Some(Opcode::LDR) => self.op_ldr(instr),
-                Some(Opcode::LEA) => self.op_lea(instr),
-                Some(Opcode::NOT) => self.op_not(instr),
-                Some(Opcode::RTI) => self.op_rti(instr),
-                Some(Opcode::ST) => self.op_st(instr),
-                Some(Opcode::STI) => self.op_sti(instr),
-                Some(Opcode::STR) => self.op_str(instr),
-                Some(Opcode::TRAP) => self.op_trap(instr),
-                _ => self.bad_opcode(),
+    /// Executes at most `count` instructions.
+    pub fn run_for(&mut self, count: u64) -> StopReason {
+        self.execute(Some(count), |_| false)
+    }
+
+    /// Executes until `predicate` returns true. The predicate is checked before every
+    /// instruction except the first one, so calling this again resumes execution.
+    pub fn run_until<F>(&mut self, predicate: F) -> StopReason
+    where
+        F: FnMut(&Self) -> bool,
+    {
+        self.execute(None, predicate)
+    }
+
+    /// Executes from the current PC until the machine stops.
+    pub fn run(&mut self) -> StopReason {
+        self.run_until(|_| false)
+    }
+
+    fn execute<F>(&mut self, limit: Option<u64>, mut predicate: F) -> StopReason
+    where
+        F: FnMut(&Self) -> bool,
+    {
+        self.set_clock_enable(true);
+        let mut executed = 0;
+        loop {
+            if limit.map_or(false, |limit| executed >= limit) {
+                return StopReason::InstructionLimit;
+            }
+            if executed > 0
+                && (self.breakpoints.contains(&self.registers[Register::PC]) || predicate(self))
+            {
+                return StopReason::Breakpoint;
+            }
+            // interrupts are taken before the PC to roll back to is noted, so that a fault or
+            // a wait in the first instruction of a handler leaves the machine in the handler
+            let interrupted = self.registers[Register::PC];
+            if let Err(error) = self.service_interrupts() {
+                self.registers[Register::PC] = interrupted;
+                self.set_clock_enable(false);
+                return StopReason::Fault(Fault {
+                    error,
+                    pc: interrupted,
+                    instr: 0,
+                });
+            }
+            let pc = self.registers[Register::PC];
+            if let Err((error, instr)) = self.execute_instruction() {
+                self.registers[Register::PC] = pc;
+                self.set_clock_enable(false);
+                return StopReason::Fault(Fault { error, pc, instr });
+            }
+            if self.waiting_for_input {
+                self.waiting_for_input = false;
+                self.registers[Register::PC] = pc;
+                return StopReason::WaitingForInput;
+            }
+            executed += 1;
+            self.cycles += 1;
+            self.bus.tick(1);
+            // the guest stops the clock by clearing MCR[15]
+            if !self.clock_enabled() {
+                return StopReason::Halted;
             }
         }
     }
 
-    fn bad_opcode(&mut self) {
-        trace!("BAD");
-        println!("bad opcode");
+    fn clock_enabled(&self) -> bool {
+        let mcr = self.bus.peek(MemoryMappedRegister::MCR as u16).unwrap_or(0);
+        mcr & MCR_CLOCK_ENABLE != 0
     }
 
-    fn op_add(&mut self, instr: u16) {
-        // destination register (DR)
-        let r0: u16 = (instr >> 9) & 0x7;
-        // first operand (SR1)
-        let r1: u16 = (instr >> 6) & 0x7;
-        // whether we are in immediate mode
-        let imm_flag = (instr >> 5) & 0x1;
-        if imm_flag == 1 {
-            let imm5 = sign_extend(instr & 0x1F, 5);
-            trace!("ADD DR: {} SR1: {} IMM: {}", r0, r1, imm5);
-            self.registers[Register::from_u16(r0)] =
-                self.registers[Register::from_u16(r1)].wrapping_add(imm5);
-        } else {
-            let r2 = instr & 0x7;
-            trace!("ADD DR: {} SR1: {} R2: {}", r0, r1, r2);
-            self.registers[Register::from_u16(r0)] = self.registers[Register::from_u16(r1)]
-                .wrapping_add(self.registers[Register::from_u16(r2)]);
+    fn set_clock_enable(&mut self, enable: bool) {
+        let addr = MemoryMappedRegister::MCR as u16;
+        let mcr = self.bus.peek(addr).unwrap_or(0) & !MCR_CLOCK_ENABLE;
+        let _ = self
+            .bus
+            .write(addr, if enable { mcr | MCR_CLOCK_ENABLE } else { mcr });
+    }
+
+    /// Fetches and executes the instruction at PC, turning errors into exceptions when the
+    /// OS has installed a handler for them. On failure, returns the error together with the
+    /// instruction word, which is 0 if the fetch itself failed.
+    fn execute_instruction(&mut self) -> Result<(), (VmError, u16)> {
+        self.send_diagnostics();
+        match self.fetch_and_execute() {
+            Err((error, _)) if self.raise_exception(&error) => Ok(()),
+            result => result,
         }
-        self.update_flags(Register::from_u16(r0));
     }
 
-    fn op_and(&mut self, instr: u16) {
-        // destination register (DR)
-        let r0: u16 = (instr >> 9) & 0x7;
-        // first operand (SR1)
-        let r1: u16 = (instr >> 6) & 0x7;
-        // whether we are in immediate mode
-        let imm_flag = (instr >> 5) & 0x1;
+    fn fetch_and_execute(&mut self) -> Result<(), (VmError, u16)> {
+        let instr = self
+            .mem_read(self.registers[Register::PC])
+            .map_err(|e| (e, 0))?;
+        self.registers[Register::PC] = self.registers[Register::PC].wrapping_add(1);
+        let op = instr >> 12;
+        trace!(
+            "PC: {:x}{} INSTR: {:016b} OP: {:04b} {}",
+            self.registers[Register::PC].wrapping_sub(1),
+            self.symbols
+                .label(self.registers[Register::PC].wrapping_sub(1))
+                .map(|label| format!(" <{}>", label))
+                .unwrap_or_default(),
+            instr,
+            op,
+            disassemble_instruction_at(
+                instr,
+                self.registers[Register::PC].wrapping_sub(1),
+                &self.symbols
+            )
+            .unwrap_or_default()
+        );
+        //::std::thread::sleep(::std::time::Duration::from_millis(500));
+        let instruction = match decode(instr) {
+            Ok(instruction) => instruction,
+            Err(DecodeError::Reserved) => return Err((VmError::ReservedOpcode, instr)),
+        };
+        match instruction {
+            Instruction::Add { dr, sr1, operand } => self.op_add(dr, sr1, operand),
+            Instruction::And { dr, sr1, operand } => self.op_and(dr, sr1, operand),
+            Instruction::Br { nzp, offset } => self.op_br(nzp, offset),
+            Instruction::Jmp { base } => self.op_jmp(base),
+            Instruction::Jsr { offset } => self.op_jsr(None, offset),
+            Instruction::Jsrr { base } => self.op_jsr(Some(base), 0),
+            Instruction::Ld { dr, offset } => self.op_ld(dr, offset),
+            Instruction::Ldi { dr, offset } => self.op_ldi(dr, offset),
+            Instruction::Ldr { dr, base, offset } => self.op_ldr(dr, base, offset),
+            Instruction::Lea { dr, offset } => self.op_lea(dr, offset),
+            Instruction::Not { dr, sr } => self.op_not(dr, sr),
+            Instruction::Rti => self.op_rti(),
+            Instruction::St { sr, offset } => self.op_st(sr, offset),
+            Instruction::Sti { sr, offset } => self.op_sti(sr, offset),
+            Instruction::Str { sr, base, offset } => self.op_str(sr, base, offset),
+            Instruction::Trap { vector } => self.op_trap(vector),
+        }
+        .map_err(|e| (e, instr))
+    }
 
-        if imm_flag == 1 {
-            let imm5 = sign_extend(instr & 0x1F, 5);
-            trace!("AND R0: {} R1: {} IMM: {}", r0, r1, imm5);
-            self.registers[Register::from_u16(r0)] = self.registers[Register::from_u16(r1)] & imm5;
-        } else {
-            let r2 = instr & 0x7;
-            trace!("AND R0: {} R1: {} R2: {}", r0, r1, r2);
-            self.registers[Register::from_u16(r0)] =
-                self.registers[Register::from_u16(r1)] & self.registers[Register::from_u16(r2)];
+    /// Starts the service routine of the highest priority interrupt, if any, whose priority
+    /// is above that of the running program. Devices interrupt for as long as they request
+    /// it.
+    fn service_interrupts(&mut self) -> Result<(), VmError> {
+        let current_priority = ((self.registers[Register::PSR] & PSR_PRIORITY_MASK) >> 8) as u8;
+        let highest = self
+            .bus
+            .interrupts()
+            .into_iter()
+            .filter(|(_, priority)| *priority > current_priority)
+            .max_by_key(|(_, priority)| *priority);
+        if let Some((vector, priority)) = highest {
+            let handler = self.bus.peek(INTERRUPT_VECTOR_TABLE + u16::from(vector))?;
+            trace!("INTERRUPT VECTOR: {:02X} HANDLER: {:04X}", vector, handler);
+            self.initiate_service_routine(handler, Some(u16::from(priority)))?;
+        }
+        Ok(())
+    }
+
+    /// Dispatches `error` through the exception vector table. Returns false if the error has
+    /// no exception vector or no handler is installed for it, in which case the caller
+    /// reports it as a fault instead.
+    fn raise_exception(&mut self, error: &VmError) -> bool {
+        let vector = match error {
+            VmError::PrivilegeViolation => ExceptionVector::PrivilegeViolation,
+            VmError::ReservedOpcode => ExceptionVector::IllegalOpcode,
+            VmError::AccessViolation(_) => ExceptionVector::AccessViolation,
+            _ => return false,
+        } as u16;
+        match self.bus.peek(EXCEPTION_VECTOR_TABLE + vector) {
+            Ok(handler) if handler != 0 => {
+                trace!("EXCEPTION VECTOR: {:02X} HANDLER: {:04X}", vector, handler);
+                self.initiate_service_routine(handler, None).is_ok()
+            }
+            _ => false,
         }
     }
 
-    fn op_br(&mut self, instr: u16) {
-        let cond_flag: u16 = (instr >> 9) & 0x7;
-        let n = if cond_flag & ConditionFlags::NEG as u16 == 1 {
+    /// Enters supervisor mode, switching to the supervisor stack if needed, pushes the
+    /// interrupted PSR and PC and continues at `handler`. Interrupts also pass the
+    /// `priority` that PSR[10:8] is raised to.
+    fn initiate_service_routine(
+        &mut self,
+        handler: u16,
+        priority: Option<u16>,
+    ) -> Result<(), VmError> {
+        let psr = self.registers[Register::PSR];
+        if psr & PSR_USER_MODE != 0 {
+            self.registers[Register::USP] = self.registers[Register::R6];
+            self.registers[Register::R6] = self.registers[Register::SSP];
+        }
+        let mut new_psr = psr & !PSR_USER_MODE;
+        if let Some(priority) = priority {
+            new_psr = (new_psr & !PSR_PRIORITY_MASK) | ((priority << 8) & PSR_PRIORITY_MASK);
+        }
+        self.registers[Register::PSR] = new_psr;
+        self.push(psr)?;
+        self.push(self.registers[Register::PC])?;
+        self.registers[Register::PC] = handler;
+        Ok(())
+    }
+
+    fn push(&mut self, value: u16) -> Result<(), VmError> {
+        self.registers[Register::R6] = self.registers[Register::R6].wrapping_sub(1);
+        self.mem_write(self.registers[Register::R6], value)
+    }
+
+    fn pop(&mut self) -> Result<u16, VmError> {
+        let value = self.mem_read(self.registers[Register::R6])?;
+        self.registers[Register::R6] = self.registers[Register::R6].wrapping_add(1);
+        Ok(value)
+    }
+
+    /// The value of the second operand of ADD and AND.
+    fn alu_operand(&self, operand: AluOperand) -> u16 {
+        match operand {
+            AluOperand::Register(r) => self.registers[Register::from_u16(r)],
+            AluOperand::Immediate(imm) => imm as u16,
+        }
+    }
+
+    fn op_add(&mut self, dr: u16, sr1: u16, operand: AluOperand) -> Result<(), VmError> {
+        trace!("ADD DR: {} SR1: {} OPERAND: {:?}", dr, sr1, operand);
+        self.registers[Register::from_u16(dr)] =
+            self.registers[Register::from_u16(sr1)].wrapping_add(self.alu_operand(operand));
+        self.update_flags(Register::from_u16(dr));
+        Ok(())
+    }
+
+    fn op_and(&mut self, dr: u16, sr1: u16, operand: AluOperand) -> Result<(), VmError> {
+        trace!("AND DR: {} SR1: {} OPERAND: {:?}", dr, sr1, operand);
+        self.registers[Register::from_u16(dr)] =
+            self.registers[Register::from_u16(sr1)] & self.alu_operand(operand);
+        self.update_flags(Register::from_u16(dr));
+        Ok(())
+    }
+
+    fn op_br(&mut self, nzp: u16, pc_offset: i16) -> Result<(), VmError> {
+        let n = if nzp & ConditionFlags::NEG as u16 != 0 {
             "n"
         } else {
             ""
         };
-        let z = if cond_flag & ConditionFlags::ZRO as u16 == 1 {
+        let z = if nzp & ConditionFlags::ZRO as u16 != 0 {
             "z"
         } else {
             ""
         };
-        let p = if cond_flag & ConditionFlags::POS as u16 == 1 {
+        let p = if nzp & ConditionFlags::POS as u16 != 0 {
             "p"
         } else {
             ""
         };
-        let pc_offset = sign_extend(instr & 0x1ff, 9);
         trace!("BR{}{}{} OFFSET: {}", n, z, p, pc_offset);
-        if cond_flag & self.registers[Register::COND] != 0 {
-            self.registers[Register::PC] = self.registers[Register::PC].wrapping_add(pc_offset);
+        if nzp & self.registers[Register::PSR] & PSR_CONDITION_MASK != 0 {
+            self.registers[Register::PC] = self.pc_relative(pc_offset);
         }
+        Ok(())
     }
 
-    fn op_jmp(&mut self, instr: u16) {
-        let r0 = (instr >> 6) & 0x7;
-        let value = self.registers[Register::from_u16(r0)];
-        trace!("JMP BASER: {} VAL: {:b}", r0, value);
+    fn op_jmp(&mut self, base: u16) -> Result<(), VmError> {
+        let value = self.registers[Register::from_u16(base)];
+        trace!("JMP BASER: {} VAL: {:b}", base, value);
         self.registers[Register::PC] = value;
+        Ok(())
     }
 
-    fn op_jsr(&mut self, instr: u16) {
+    /// JSRR jumps to the address in `base`, JSR to PC plus `pc_offset`.
+    fn op_jsr(&mut self, base: Option<u16>, pc_offset: i16) -> Result<(), VmError> {
         trace!("JSR");
-        self.registers[Register::R7] = self.registers[Register::PC];
-        let long_flag = (instr >> 11) & 0x1;
-        if long_flag == 0 {
-            let base_r = (instr >> 6) & 0x7;
-            self.registers[Register::PC] = self.registers[Register::from_u16(base_r)];
-        } else {
-            self.registers[Register::PC] =
-                self.registers[Register::PC].wrapping_add(sign_extend(instr & 0x7ff, 11));
-        }
+        let return_address = self.registers[Register::PC];
+        self.registers[Register::PC] = match base {
+            // the base register is read before R7 is written, so JSRR R7 works
+            Some(base) => self.registers[Register::from_u16(base)],
+            None => self.pc_relative(pc_offset),
+        };
+        self.registers[Register::R7] = return_address;
+        Ok(())
     }
 
-    fn op_ld(&mut self, instr: u16) {
-        let dr: u16 = (instr >> 9) & 0x7;
-        let pc_offset = sign_extend(instr & 0x1ff, 9);
+    fn op_ld(&mut self, dr: u16, pc_offset: i16) -> Result<(), VmError> {
         trace!("LD DR: {} OFFSET: {}", dr, pc_offset);
-        self.registers[Register::from_u16(dr)] =
-            self.mem_read(self.registers[Register::PC].wrapping_add(pc_offset));
+        self.registers[Register::from_u16(dr)] = self.mem_read(self.pc_relative(pc_offset))?;
         self.update_flags(Register::from_u16(dr));
+        Ok(())
     }
 
-    fn op_ldi(&mut self, instr: u16) {
+    fn op_ldi(&mut self, dr: u16, pc_offset: i16) -> Result<(), VmError> {
         trace!("LDI");
-        // destination register (DR)
-        let r0: u16 = (instr >> 9) & 0x7;
-        // PCoffset 9
-        let pc_offset = sign_extend(instr & 0x1ff, 9);
-        // add pc_offset to the current PC, look at that memory location to get the final address
-
-        let thing1 = self.mem_read(self.registers[Register::PC] + pc_offset);
-        self.registers[Register::from_u16(r0)] = self.mem_read(thing1);
-        self.update_flags(Register::from_u16(r0));
+        // the word at PC plus the offset is the address to load from
+        let addr = self.mem_read(self.pc_relative(pc_offset))?;
+        self.registers[Register::from_u16(dr)] = self.mem_read(addr)?;
+        self.update_flags(Register::from_u16(dr));
+        Ok(())
     }
 
-    fn op_ldr(&mut self, instr: u16) {
+    fn op_ldr(&mut self, dr: u16, base: u16, offset: i16) -> Result<(), VmError> {
         trace!("LDR");
-        let dr: u16 = (instr >> 9) & 0x7;
-        let base_r = self.registers[Register::from_u16((instr >> 6) & 0x7)];
-        let offset = sign_extend(instr & 0x3f, 5);
-        self.registers[Register::from_u16(dr)] = self.mem_read(base_r + offset);
+        let base_r = self.registers[Register::from_u16(base)];
+        self.registers[Register::from_u16(dr)] =
+            self.mem_read(base_r.wrapping_add(offset as u16))?;
         self.update_flags(Register::from_u16(dr));
+        Ok(())
     }
 
-    fn op_lea(&mut self, instr: u16) {
+    fn op_lea(&mut self, dr: u16, pc_offset: i16) -> Result<(), VmError> {
         trace!("LEA");
-        let dr: u16 = (instr >> 9) & 0x7;
-        let pc_offset = sign_extend(instr & 0x1ff, 9);
-        self.registers[Register::from_u16(dr)] = self.registers[Register::PC] + pc_offset;
+        self.registers[Register::from_u16(dr)] = self.pc_relative(pc_offset);
         self.update_flags(Register::from_u16(dr));
+        Ok(())
     }
 
-    fn op_not(&mut self, instr: u16) {
+    fn op_not(&mut self, dr: u16, sr: u16) -> Result<(), VmError> {
         trace!("NOT");
-        let dr: u16 = (instr >> 9) & 0x7;
-        let sr: u16 = (instr >> 6) & 0x7;
         self.registers[Register::from_u16(dr)] = !self.registers[Register::from_u16(sr)];
         self.update_flags(Register::from_u16(dr));
+        Ok(())
     }
 
-    fn op_rti(&mut self, _instr: u16) {
+    fn op_rti(&mut self) -> Result<(), VmError> {
         trace!("RTI");
-        if (self.registers[Register::PSR] >> 15 & 1) == 0 {
-            self.registers[Register::PC] = self.mem_read(self.registers[Register::R6]); // R6 is the SSP
-            self.registers[Register::R6] += 1;
-            let temp = self.mem_read(self.registers[Register::R6]);
-            self.registers[Register::R6] += 1;
+        if self.registers[Register::PSR] & PSR_USER_MODE == 0 {
+            self.registers[Register::PC] = self.pop()?; // R6 is the SSP
+            let temp = self.pop()?;
             self.registers[Register::PSR] = temp;
-        // the privilege mode and condition codes of the interrupted process are restored
+            // the privilege mode and condition codes of the interrupted process are restored
+            if temp & PSR_USER_MODE != 0 {
+                self.registers[Register::SSP] = self.registers[Register::R6];
+                self.registers[Register::R6] = self.registers[Register::USP];
+            }
+            Ok(())
         } else {
-            // initiate a privilege mode exception
+            Err(VmError::PrivilegeViolation)
         }
     }
 
-    fn op_st(&mut self, instr: u16) {
+    fn op_st(&mut self, sr: u16, pc_offset: i16) -> Result<(), VmError> {
         trace!("ST");
-        let sr = self.registers[Register::from_u16((instr >> 9) & 0x7)];
-        let pc_offset = sign_extend(instr & 0x1ff, 9);
-        self.mem_write(self.registers[Register::PC].wrapping_add(pc_offset), sr);
+        let value = self.registers[Register::from_u16(sr)];
+        self.mem_write(self.pc_relative(pc_offset), value)
     }
 
-    fn op_sti(&mut self, instr: u16) {
+    fn op_sti(&mut self, sr: u16, pc_offset: i16) -> Result<(), VmError> {
         trace!("STI");
-        let sr = (instr >> 9) & 0x7;
-        let pc_offset = sign_extend(instr & 0x1ff, 9);
-
-        let thing = self.registers[Register::PC] + pc_offset;
-        let thing = self.mem_read(thing);
-
-        self.mem_write(thing, self.registers[Register::from_u16(sr)]);
+        let addr = self.mem_read(self.pc_relative(pc_offset))?;
+        self.mem_write(addr, self.registers[Register::from_u16(sr)])
     }
 
-    fn op_str(&mut self, instr: u16) {
-        let sr = (instr >> 9) & 0x7;
-        let base_r = (instr >> 6) & 0x7;
-        let offset = sign_extend(instr & 0x3F, 6);
-        let base_r_contents = self.registers[Register::from_u16(base_r)];
+    fn op_str(&mut self, sr: u16, base: u16, offset: i16) -> Result<(), VmError> {
+        let base_r_contents = self.registers[Register::from_u16(base)];
         let data_to_write = self.registers[Register::from_u16(sr)];
 
-        trace!("STR SR: {} BASER: {} OFFSET: {}", sr, base_r, offset);
+        trace!("STR SR: {} BASER: {} OFFSET: {}", sr, base, offset);
 
-        self.mem_write(base_r_contents.wrapping_add(offset), data_to_write);
+        self.mem_write(base_r_contents.wrapping_add(offset as u16), data_to_write)
     }
 
-    fn op_trap(&mut self, instr: u16) {
+    /// The incremented PC plus `offset`.
+    fn pc_relative(&self, offset: i16) -> u16 {
+        self.registers[Register::PC].wrapping_add(offset as u16)
+    }
+
+    fn op_trap(&mut self, vector: u8) -> Result<(), VmError> {
         trace!("TRAP");
-        let trapvect = instr & 0xff;
-        match TrapCode::from_u16(trapvect) {
-            Some(TrapCode::GetC) => self.trap_getc(),
-            Some(TrapCode::Out) => self.trap_out(),
-            Some(TrapCode::Puts) => self.trap_puts(),
-            Some(TrapCode::In) => self.trap_in(),
-            Some(TrapCode::PutSp) => self.trap_putsp(),
-            Some(TrapCode::Halt) => self.trap_halt(),
-            _ => self.bad_opcode(),
-        };
+        let trapvect = u16::from(vector);
+        // the handler is taken out for the duration of the call, so that it can borrow the VM
+        if let Some(mut handler) = self.trap_handlers.0.remove(&vector) {
+            let result = handler(self);
+            self.trap_handlers.0.entry(vector).or_insert(handler);
+            return result;
+        }
+        if self.trap_mode == TrapMode::Native {
+            match TrapCode::from_u16(trapvect) {
+                Some(TrapCode::GetC) => return self.trap_getc(),
+                Some(TrapCode::Out) => return self.trap_out(),
+                Some(TrapCode::Puts) => return self.trap_puts(),
+                Some(TrapCode::In) => return self.trap_in(),
+                Some(TrapCode::PutSp) => return self.trap_putsp(),
+                Some(TrapCode::Halt) => return self.trap_halt(),
+                Some(TrapCode::Exit) => return self.trap_exit(),
+                None => {}
+            }
+        }
+        // like an interrupt, the service routine runs in supervisor mode and returns with RTI
+        match self.bus.peek(TRAP_VECTOR_TABLE + trapvect) {
+            Ok(handler) if handler != 0 => self.initiate_service_routine(handler, None),
+            _ => Err(VmError::UnknownTrap(vector)),
+        }
+    }
+
+    /// Reads a key for GETC and IN. Returns `None` if the console has no key yet, in which
+    /// case the trap is retried once the caller provides one.
+    fn read_key(&mut self) -> Result<Option<u8>, VmError> {
+        let mut console = self.console.borrow_mut();
+        if !console.poll() {
+            self.waiting_for_input = true;
+            return Ok(None);
+        }
+        console.read().map(Some).ok_or(VmError::InputEof)
     }
 
-    fn trap_getc(&mut self) {
+    fn trap_getc(&mut self) -> Result<(), VmError> {
         trace!("GETC");
-        use std::io::Read;
-        self.registers[Register::R0] =
-            u16::from(::std::io::stdin().lock().bytes().next().unwrap().unwrap());
+        if let Some(key) = self.read_key()? {
+            self.registers[Register::R0] = u16::from(key);
+        }
+        Ok(())
     }
 
-    fn trap_out(&self) {
+    fn trap_out(&mut self) -> Result<(), VmError> {
         trace!("OUT");
         let r0_contents = self.registers[Register::R0];
         let bottom_half = r0_contents as u8;
-        print!("{}", bottom_half as char);
+        self.console.borrow_mut().write(&[bottom_half]);
+        Ok(())
     }
 
-    fn trap_puts(&self) {
+    fn trap_puts(&mut self) -> Result<(), VmError> {
         trace!("PUTS");
-        let r0_contents = self.registers[Register::R0];
-        let string_bytes = self.memory[r0_contents as usize..]
-            .iter()
-            .map(|word| *word as u8)
-            .take_while(|byte| *byte != 0)
-            .collect::<Vec<u8>>();
-        print!("{}", String::from_utf8_lossy(&string_bytes));
+        let mut index = self.registers[Register::R0];
+        let mut string_bytes = Vec::new();
+        loop {
+            let byte = self.mem_read(index)? as u8;
+            if byte == 0 {
+                break;
+            }
+            string_bytes.push(byte);
+            index = index.wrapping_add(1);
+        }
+        self.console.borrow_mut().write(&string_bytes);
+        Ok(())
     }
 
-    fn trap_in(&mut self) {
+    fn trap_in(&mut self) -> Result<(), VmError> {
         trace!("IN");
-        print!("IN: ");
-        self.trap_getc();
+        if !self.console.borrow_mut().poll() {
+            self.waiting_for_input = true;
+            return Ok(());
+        }
+        self.console.borrow_mut().write(b"IN: ");
+        self.trap_getc()
     }
 
-    fn trap_putsp(&mut self) {
+    fn trap_putsp(&mut self) -> Result<(), VmError> {
         trace!("PUTSP");
         let mut index = self.registers[Register::R0];
+        let mut string_bytes = Vec::new();
         loop {
-            let word = self.mem_read(index);
+            let word = self.mem_read(index)?;
             let first_char = word as u8;
             if first_char == 0 {
                 break;
             }
-            print!("{}", first_char as char);
+            string_bytes.push(first_char);
             let second_char = (word >> 8) as u8;
             if second_char == 0 {
                 break;
             }
-            print!("{}", second_char);
-            index += 1;
+            string_bytes.push(second_char);
+            index = index.wrapping_add(1);
         }
+        self.console.borrow_mut().write(&string_bytes);
+        Ok(())
     }
 
-    fn trap_halt(&mut self) {
+    fn trap_halt(&mut self) -> Result<(), VmError> {
         trace!("HALT");
-        println!("HALTING");
-        self.running = false;
+        self.console.borrow_mut().write(b"HALTING\n");
+        // same as the OS HALT routine, which clears the clock enable bit of the MCR
+        self.set_clock_enable(false);
+        Ok(())
     }
 
-    fn mem_write(&mut self, addr: u16, val: u16) {
-        self.memory[addr as usize] = val;
+    fn trap_exit(&mut self) -> Result<(), VmError> {
+        trace!("EXIT");
+        self.exit(self.registers[Register::R0]);
+        Ok(())
     }
 
-    fn check_key(&self) -> bool {
-        true
+    /// User mode may only touch user space; system space and the device registers are
+    /// reserved for the supervisor. Host trap handlers check the buffers they are passed
+    /// with this too.
+    pub fn check_access(&self, addr: u16) -> Result<(), VmError> {
+        if self.registers[Register::PSR] & PSR_USER_MODE != 0 && !USER_SPACE.contains(&addr) {
+            return Err(VmError::AccessViolation(addr));
+        }
+        Ok(())
     }
 
-    fn mem_read(&mut self, addr: u16) -> u16 {
-        use std::io::Read;
-        if addr == MemoryMappedRegister::KBSR as u16 {
-            if self.check_key() {
-                self.memory[MemoryMappedRegister::KBSR as usize] = 1 << 15;
-                self.memory[MemoryMappedRegister::KBDR as usize] =
-                    u16::from(::std::io::stdin().lock().bytes().next().unwrap().unwrap());
-            } else {
-                self.memory[MemoryMappedRegister::KBSR as usize] = 0;
-            }
-        }
-        self.memory[addr as usize]
+    fn mem_write(&mut self, addr: u16, val: u16) -> Result<(), VmError> {
+        self.check_access(addr)?;
+        self.bus.write(addr, val)
+    }
+
+    fn mem_read(&mut self, addr: u16) -> Result<u16, VmError> {
+        self.check_access(addr)?;
+        self.bus.read(addr)
     }
 
     fn update_flags(&mut self, reg: Register) {
-        self.registers[Register::COND] = match self.registers[reg] {
+        let flag = match self.registers[reg] {
             0 => ConditionFlags::ZRO,
             x if (x >> 15) == 1 => {
                 // a 1 in the left-most bit indicates negative
@@ -431,5 +872,585 @@ impl VirtualMachine {
             }
             _ => ConditionFlags::POS,
         } as u16;
+        self.registers[Register::PSR] =
+            (self.registers[Register::PSR] & !PSR_CONDITION_MASK) | flag;
+    }
+}
+
+#[cfg(test)]
+fn vm_with_program(origin: u16, program: &[u16]) -> VirtualMachine {
+    let mut image = Vec::new();
+    for word in std::iter::once(&origin).chain(program) {
+        image.extend_from_slice(&word.to_be_bytes());
     }
+    let mut vm = VirtualMachine::with_memory(u16::max_value() as usize + 1);
+    vm.read_image(&image).unwrap();
+    vm.set_register(Register::PC, origin);
+    vm
+}
+
+/// Where `map_interrupt_line` puts the line's register.
+#[cfg(test)]
+const INTERRUPT_LINE: u16 = 0xFE10;
+
+/// A device for tests that requests an interrupt for as long as its register is nonzero,
+/// so that writing 1 raises the interrupt and writing 0 acknowledges it.
+#[cfg(test)]
+#[derive(Debug)]
+struct InterruptLine {
+    vector: u8,
+    priority: u8,
+    raised: u16,
+}
+
+#[cfg(test)]
+impl crate::bus::Device for InterruptLine {
+    fn name(&self) -> &str {
+        "interrupt line"
+    }
+
+    fn read(&mut self, offset: u16) -> u16 {
+        self.peek(offset)
+    }
+
+    fn write(&mut self, _offset: u16, value: u16) {
+        self.raised = value;
+    }
+
+    fn peek(&self, _offset: u16) -> u16 {
+        self.raised
+    }
+
+    fn interrupt(&mut self) -> Option<(u8, u8)> {
+        if self.raised != 0 {
+            Some((self.vector, self.priority))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+fn map_interrupt_line(vm: &mut VirtualMachine, vector: u8, priority: u8) {
+    let line = InterruptLine {
+        vector,
+        priority,
+        raised: 0,
+    };
+    vm.bus.map(INTERRUPT_LINE..=INTERRUPT_LINE, Box::new(line));
+}
+
+#[test]
+fn test_step() {
+    let mut vm = vm_with_program(
+        0x3000,
+        &[
+            0b0001_000_000_1_00001, // ADD R0, R0, #1
+            0b0001_000_000_1_00010, // ADD R0, R0, #2
+        ],
+    );
+    assert_eq!(vm.run_for(1), StopReason::InstructionLimit);
+    assert_eq!(vm.register(Register::R0), 1);
+    assert_eq!(vm.register(Register::PC), 0x3001);
+    assert_eq!(vm.run_for(1), StopReason::InstructionLimit);
+    assert_eq!(vm.register(Register::R0), 3);
+}
+
+#[test]
+fn test_run_for_and_halt() {
+    let mut vm = vm_with_program(
+        0x3000,
+        &[
+            0b0001_000_000_1_00001, // ADD R0, R0, #1
+            0b0000_111_111111110,   // BRnzp #-2
+        ],
+    );
+    assert_eq!(vm.run_for(10), StopReason::InstructionLimit);
+    assert_eq!(vm.register(Register::R0), 5);
+
+    let mut vm = vm_with_program(0x3000, &[0xF025]); // HALT
+    assert_eq!(vm.run(), StopReason::Halted);
+    assert_eq!(vm.register(Register::PC), 0x3001);
+}
+
+#[test]
+fn test_breakpoints_and_predicates() {
+    let mut vm = vm_with_program(
+        0x3000,
+        &[
+            0b0001_000_000_1_00001, // ADD R0, R0, #1
+            0b0000_111_111111110,   // BRnzp #-2
+        ],
+    );
+    vm.add_breakpoint(0x3001);
+    assert_eq!(vm.run(), StopReason::Breakpoint);
+    assert_eq!(vm.register(Register::PC), 0x3001);
+    // resuming does not stop on the breakpoint we are sitting on
+    assert_eq!(vm.run(), StopReason::Breakpoint);
+    assert_eq!(vm.register(Register::R0), 2);
+    assert!(vm.remove_breakpoint(0x3001));
+
+    assert_eq!(
+        vm.run_until(|vm| vm.register(Register::R0) == 7),
+        StopReason::Breakpoint
+    );
+    assert_eq!(vm.register(Register::R0), 7);
+}
+
+#[test]
+fn test_faults() {
+    let mut vm = vm_with_program(0x3000, &[0xD000]); // RES
+    assert_eq!(
+        vm.run(),
+        StopReason::Fault(Fault {
+            error: VmError::ReservedOpcode,
+            pc: 0x3000,
+            instr: 0xD000,
+        })
+    );
+    assert_eq!(vm.register(Register::PC), 0x3000);
+
+    let mut vm = vm_with_program(0x3000, &[0xF0AA]); // TRAP xAA
+    match vm.run() {
+        StopReason::Fault(fault) => assert_eq!(fault.error, VmError::UnknownTrap(0xAA)),
+        other => panic!("unexpected stop reason {:?}", other),
+    }
+
+    let mut vm = VirtualMachine::with_memory(0x4000);
+    vm.read_image(&[0x30, 0x00, 0b0110_0000, 0b0100_0000]) // LDR R0, R1, #0
+        .unwrap();
+    vm.set_register(Register::R1, 0x8000);
+    match vm.run() {
+        StopReason::Fault(fault) => assert_eq!(fault.error, VmError::AccessViolation(0x8000)),
+        other => panic!("unexpected stop reason {:?}", other),
+    }
+}
+
+#[test]
+fn test_load_errors() {
+    let mut vm = VirtualMachine::with_memory(0x100);
+    assert!(vm.read_image(&[0x30]).is_err());
+    assert!(vm.read_image(&[0x30, 0x00, 0x12, 0x34]).is_err());
+    assert_eq!(vm.read_image(&[0x00, 0x10, 0x12, 0x34]), Ok(()));
+}
+
+#[test]
+fn test_exceptions_and_rti() {
+    let mut vm = vm_with_program(
+        0x3000,
+        &[
+            0b1000_000000000000, // RTI
+            0xF025,              // HALT
+        ],
+    );
+    // no handler installed: the privilege violation is reported as a fault
+    vm.set_register(Register::PSR, PSR_USER_MODE);
+    match vm.run() {
+        StopReason::Fault(fault) => assert_eq!(fault.error, VmError::PrivilegeViolation),
+        other => panic!("unexpected stop reason {:?}", other),
+    }
+
+    // handler at x1000 just returns to the instruction after the RTI
+    vm.write_memory(EXCEPTION_VECTOR_TABLE, 0x1000).unwrap();
+    vm.write_memory(0x1000, 0b1000_000000000000).unwrap(); // RTI
+    vm.set_register(Register::R6, 0x4000);
+    vm.set_register(Register::SSP, 0x2000);
+    assert_eq!(vm.run_for(1), StopReason::InstructionLimit);
+    assert_eq!(vm.register(Register::PC), 0x1000);
+    assert_eq!(vm.register(Register::PSR) & PSR_USER_MODE, 0);
+    assert_eq!(vm.register(Register::R6), 0x1FFE);
+    assert_eq!(vm.register(Register::USP), 0x4000);
+    assert_eq!(vm.read_memory(0x1FFF), Ok(PSR_USER_MODE));
+    assert_eq!(vm.read_memory(0x1FFE), Ok(0x3001));
+
+    assert_eq!(vm.run_for(1), StopReason::InstructionLimit);
+    assert_eq!(vm.register(Register::PC), 0x3001);
+    assert_eq!(vm.register(Register::PSR), PSR_USER_MODE);
+    assert_eq!(vm.register(Register::R6), 0x4000);
+    assert_eq!(vm.register(Register::SSP), 0x2000);
+}
+
+#[test]
+fn test_user_mode_access_violation() {
+    let mut vm = vm_with_program(0x3000, &[0b1010_000_000000000]); // LDI R0, #0
+    vm.write_memory(0x3001, 0x0200).unwrap();
+    vm.set_register(Register::PSR, PSR_USER_MODE);
+    match vm.run() {
+        StopReason::Fault(fault) => assert_eq!(fault.error, VmError::AccessViolation(0x0200)),
+        other => panic!("unexpected stop reason {:?}", other),
+    }
+    vm.set_register(Register::PSR, 0);
+    assert_eq!(vm.run_for(1), StopReason::InstructionLimit);
+}
+
+#[test]
+fn test_user_mode_puts_checks_access() {
+    let mut vm = vm_with_program(0x3000, &[0xF022]); // PUTS
+    vm.write_memory(0x0200, u16::from(b'!')).unwrap();
+    vm.set_register(Register::R0, 0x0200);
+    vm.set_register(Register::PSR, PSR_USER_MODE);
+    match vm.run() {
+        StopReason::Fault(fault) => assert_eq!(fault.error, VmError::AccessViolation(0x0200)),
+        other => panic!("unexpected stop reason {:?}", other),
+    }
+}
+
+#[test]
+fn test_condition_codes_live_in_psr() {
+    let mut vm = vm_with_program(
+        0x3000,
+        &[
+            0b0001_000_000_1_11111, // ADD R0, R0, #-1
+        ],
+    );
+    vm.set_register(Register::PSR, PSR_USER_MODE | 0x0200);
+    vm.run_for(1);
+    assert_eq!(
+        vm.register(Register::PSR),
+        PSR_USER_MODE | 0x0200 | ConditionFlags::NEG as u16
+    );
+}
+
+#[test]
+fn test_interrupts() {
+    let mut vm = vm_with_program(
+        0x3000,
+        &[
+            0b0001_000_000_1_00001, // ADD R0, R0, #1
+            0b0001_000_000_1_00001, // ADD R0, R0, #1
+        ],
+    );
+    vm.write_memory(INTERRUPT_VECTOR_TABLE + 1, 0x1000).unwrap();
+    vm.write_memory(0x1000, 0b0001_001_001_1_00001).unwrap(); // ADD R1, R1, #1
+    vm.write_memory(0x1001, 0b1000_000000000000).unwrap(); // RTI
+    vm.set_register(Register::R6, 0x2000);
+    vm.set_register(Register::PSR, 0x0200);
+    map_interrupt_line(&mut vm, 1, 2);
+
+    // not above the current priority, stays pending
+    vm.write_memory(INTERRUPT_LINE, 1).unwrap();
+    vm.run_for(1);
+    assert_eq!(vm.register(Register::R0), 1);
+    vm.set_register(Register::PSR, 0x0100);
+    vm.run_for(1);
+    assert_eq!(vm.register(Register::R1), 1);
+    assert_eq!(vm.register(Register::PSR) & PSR_PRIORITY_MASK, 0x0200);
+    vm.write_memory(INTERRUPT_LINE, 0).unwrap();
+    vm.run_for(1);
+    assert_eq!(vm.register(Register::PC), 0x3001);
+    assert_eq!(vm.register(Register::PSR) & PSR_PRIORITY_MASK, 0x0100);
+    assert_eq!(vm.register(Register::R6), 0x2000);
+    vm.run_for(1);
+    assert_eq!(vm.register(Register::R0), 2);
+    assert_eq!(vm.register(Register::R1), 1);
+}
+
+#[test]
+fn test_interrupt_handler_fault() {
+    let mut vm = vm_with_program(0x3000, &[0b0001_000_000_1_00001]); // ADD R0, R0, #1
+    vm.write_memory(INTERRUPT_VECTOR_TABLE + 1, 0x1000).unwrap();
+    vm.write_memory(0x1000, 0xD000).unwrap(); // RES
+    vm.set_register(Register::R6, 0x2000);
+    vm.set_register(Register::PSR, PSR_USER_MODE);
+    vm.set_register(Register::SSP, 0x2000);
+
+    // the fault is reported at the handler, which is where the machine stays
+    map_interrupt_line(&mut vm, 1, 3);
+    vm.write_memory(INTERRUPT_LINE, 1).unwrap();
+    assert_eq!(
+        vm.run(),
+        StopReason::Fault(Fault {
+            error: VmError::ReservedOpcode,
+            pc: 0x1000,
+            instr: 0xD000,
+        })
+    );
+    assert_eq!(vm.register(Register::PC), 0x1000);
+    assert_eq!(vm.register(Register::PSR), 0x0300);
+    assert_eq!(vm.register(Register::R6), 0x1FFE);
+}
+
+#[test]
+fn test_interrupt_handler_waits_for_input() {
+    use crate::console::BufferConsole;
+    let mut vm = vm_with_program(0x3000, &[0b0001_000_000_1_00001]); // ADD R0, R0, #1
+    vm.write_memory(INTERRUPT_VECTOR_TABLE + 1, 0x1000).unwrap();
+    vm.set_register(Register::R6, 0x2000);
+    vm.set_register(Register::PSR, PSR_USER_MODE);
+    vm.write_memory(0x1000, 0xF020).unwrap(); // GETC
+    vm.write_memory(0x1001, 0b1000_000000000000).unwrap(); // RTI
+    vm.set_register(Register::SSP, 0x2000);
+    map_interrupt_line(&mut vm, 1, 3);
+    vm.write_memory(INTERRUPT_LINE, 1).unwrap();
+
+    // GETC without input waits in the handler, and carries on there once there is some
+    let console = BufferConsole::new();
+    vm.set_console(Box::new(console.clone()));
+    assert_eq!(vm.run_for(1), StopReason::WaitingForInput);
+    assert_eq!(vm.register(Register::PC), 0x1000);
+    assert_eq!(vm.register(Register::PSR), 0x0300);
+    vm.write_memory(INTERRUPT_LINE, 0).unwrap();
+    console.push_input(b"k");
+    assert_eq!(vm.run_for(2), StopReason::InstructionLimit);
+    assert_eq!(vm.register(Register::R0), u16::from(b'k'));
+    assert_eq!(vm.register(Register::PC), 0x3000);
+    assert_eq!(vm.register(Register::PSR), PSR_USER_MODE);
+    assert_eq!(vm.register(Register::R6), 0x2000);
+}
+
+#[test]
+fn test_keyboard_interrupt() {
+    use crate::bits::{KBSR_INTERRUPT_ENABLE, KEYBOARD_PRIORITY};
+    use crate::console::BufferConsole;
+    let mut vm = vm_with_program(0x3000, &[0b0000_111_111111111]); // BRnzp #-1
+    vm.write_memory(INTERRUPT_VECTOR_TABLE, 0x1000).unwrap();
+    vm.write_memory(0x1000, 0b1010_000_000000001).unwrap(); // LDI R0, #1
+    vm.write_memory(0x1001, 0b1000_000000000000).unwrap(); // RTI
+    vm.write_memory(0x1002, MemoryMappedRegister::KBDR as u16)
+        .unwrap();
+    vm.set_console(Box::new(BufferConsole::with_input(b"a")));
+    vm.write_memory(MemoryMappedRegister::KBSR as u16, KBSR_INTERRUPT_ENABLE)
+        .unwrap();
+    vm.set_register(Register::R6, 0x2000);
+
+    vm.run_for(1);
+    assert_eq!(vm.register(Register::R0), u16::from(b'a'));
+    assert_eq!(
+        vm.register(Register::PSR) & PSR_PRIORITY_MASK,
+        u16::from(KEYBOARD_PRIORITY) << 8
+    );
+    assert_eq!(
+        vm.read_memory(MemoryMappedRegister::KBSR as u16),
+        Ok(KBSR_INTERRUPT_ENABLE)
+    );
+    vm.write_memory(MemoryMappedRegister::KBSR as u16, 0)
+        .unwrap();
+    vm.run_for(1);
+    assert_eq!(vm.register(Register::PC), 0x3000);
+}
+
+#[test]
+fn test_console_io() {
+    use crate::console::BufferConsole;
+    let mut vm = vm_with_program(
+        0x3000,
+        &[
+            0xF020,               // GETC
+            0xF021,               // OUT
+            0b1110_000_000000010, // LEA R0, #2
+            0xF022,               // PUTS
+            0xF025,               // HALT
+            u16::from(b'o'),      // .STRINGZ "ok"
+            u16::from(b'k'),
+            0,
+        ],
+    );
+    let console = BufferConsole::new();
+    vm.set_console(Box::new(console.clone()));
+    assert_eq!(vm.run(), StopReason::WaitingForInput);
+    assert_eq!(vm.register(Register::PC), 0x3000);
+    console.push_input(b"x");
+    assert_eq!(vm.run(), StopReason::Halted);
+    assert_eq!(console.take_output(), b"xokHALTING\n");
+
+    vm.set_register(Register::PC, 0x3000);
+    console.close_input();
+    match vm.run() {
+        StopReason::Fault(fault) => assert_eq!(fault.error, VmError::InputEof),
+        other => panic!("unexpected stop reason {:?}", other),
+    }
+}
+
+#[test]
+fn test_display_registers() {
+    use crate::console::BufferConsole;
+    let mut vm = vm_with_program(
+        0x3000,
+        &[
+            0b1010_001_000000101,   // LDI R1, #5
+            0b0000_011_111111110,   // BRzp #-2
+            0b0101_000_000_1_00000, // AND R0, R0, #0
+            0b0001_000_000_1_01111, // ADD R0, R0, #15
+            0b1011_000_000000010,   // STI R0, #2
+            0xF025,                 // HALT
+            MemoryMappedRegister::DSR as u16,
+            MemoryMappedRegister::DDR as u16,
+        ],
+    );
+    let console = BufferConsole::new();
+    vm.set_console(Box::new(console.clone()));
+    assert_eq!(vm.run(), StopReason::Halted);
+    assert_eq!(console.take_output(), b"\x0fHALTING\n");
+}
+
+#[test]
+fn test_mcr_stops_the_clock() {
+    let mut vm = vm_with_program(
+        0x3000,
+        &[
+            0b1010_000_000000011,   // LDI R0, #3
+            0b0101_000_000_1_01111, // AND R0, R0, #15
+            0b1011_000_000000001,   // STI R0, #1
+            0b0000_111_111111111,   // BRnzp #-1
+            MemoryMappedRegister::MCR as u16,
+        ],
+    );
+    assert_eq!(vm.run_for(100), StopReason::Halted);
+    assert_eq!(vm.register(Register::PC), 0x3003);
+    assert_eq!(vm.register(Register::R0), 0);
+    assert_eq!(vm.read_memory(MemoryMappedRegister::MCR as u16), Ok(0));
+}
+
+#[test]
+fn test_timer_interrupt() {
+    use crate::bits::{TIMER_ENABLE, TIMER_INTERRUPT_ENABLE};
+    let mut vm = vm_with_program(
+        0x3000,
+        &[
+            0b0001_000_000_1_00001, // ADD R0, R0, #1
+            0b0000_111_111111110,   // BRnzp #-2
+        ],
+    );
+    // the handler counts interrupts in R1 and acknowledges the timer
+    vm.write_memory(INTERRUPT_VECTOR_TABLE + 1, 0x1000).unwrap();
+    for (offset, &word) in [
+        0b0001_001_001_1_00001, // ADD R1, R1, #1
+        0b1010_010_000000010,   // LDI R2, #2
+        0b1011_010_000000001,   // STI R2, #1
+        0b1000_000000000000,    // RTI
+        MemoryMappedRegister::TMCR as u16,
+    ]
+    .iter()
+    .enumerate()
+    {
+        vm.write_memory(0x1000 + offset as u16, word).unwrap();
+    }
+    vm.set_register(Register::R6, 0x2000);
+    vm.write_memory(MemoryMappedRegister::TMRR as u16, 10)
+        .unwrap();
+    vm.write_memory(MemoryMappedRegister::TMCNT as u16, 10)
+        .unwrap();
+    vm.write_memory(
+        MemoryMappedRegister::TMCR as u16,
+        TIMER_ENABLE | TIMER_INTERRUPT_ENABLE,
+    )
+    .unwrap();
+
+    assert_eq!(vm.run_for(100), StopReason::InstructionLimit);
+    assert_eq!(vm.cycles(), 100);
+    assert!(vm.register(Register::R1) >= 7);
+    assert_eq!(vm.register(Register::PSR) & PSR_PRIORITY_MASK, 0);
+}
+
+#[test]
+fn test_boot_os() {
+    use crate::console::BufferConsole;
+    let mut os = vec![0x00, 0x00];
+    let mut words = vec![0u16; 0x220];
+    words[TrapCode::Out as usize] = 0x0210;
+    words[TrapCode::Halt as usize] = 0x0218;
+    words[0x0200..0x020A].copy_from_slice(&[
+        0b0010_110_000000110,   // LD R6, SSP
+        0b0010_000_000000110,   // LD R0, USER_PSR
+        0b0111_000_110_111111,  // STR R0, R6, #-1
+        0b0010_000_000000101,   // LD R0, USER_PC
+        0b0111_000_110_111110,  // STR R0, R6, #-2
+        0b0001_110_110_1_11110, // ADD R6, R6, #-2
+        0b1000_000000000000,    // RTI
+        0x3000,                 // SSP
+        PSR_USER_MODE,          // USER_PSR
+        0x3000,                 // USER_PC
+    ]);
+    // OUT
+    words[0x0210..0x0213].copy_from_slice(&[
+        0b1011_000_000000001, // STI R0, DDR_ADDR
+        0b1000_000000000000,  // RTI
+        MemoryMappedRegister::DDR as u16,
+    ]);
+    // HALT
+    words[0x0218..0x021B].copy_from_slice(&[
+        0b0101_000_000_1_00000, // AND R0, R0, #0
+        0b1011_000_000000000,   // STI R0, MCR_ADDR
+        MemoryMappedRegister::MCR as u16,
+    ]);
+    for word in words {
+        os.extend_from_slice(&word.to_be_bytes());
+    }
+
+    let mut vm = vm_with_program(
+        0x3000,
+        &[
+            0b0101_000_000_1_00000, // AND R0, R0, #0
+            0b0001_000_000_1_01010, // ADD R0, R0, #10
+            0xF021,                 // OUT
+            0xF025,                 // HALT
+        ],
+    );
+    let console = BufferConsole::new();
+    vm.set_console(Box::new(console.clone()));
+    vm.boot_os(&os, 0x0200).unwrap();
+    assert_eq!(vm.run_for(100), StopReason::Halted);
+    assert_eq!(console.take_output(), b"\n");
+    assert_eq!(vm.register(Register::PC), 0x021A);
+    assert_eq!(vm.register(Register::PSR) & PSR_USER_MODE, 0);
+}
+
+#[test]
+fn test_host_traps() {
+    use crate::console::BufferConsole;
+    let mut vm = vm_with_program(
+        0x3000,
+        &[
+            0b0001_000_000_1_01111, // ADD R0, R0, #15
+            0xF030,                 // TRAP x30
+            0xF021,                 // OUT
+            0xF031,                 // TRAP x31
+            0xF0FF,                 // TRAP xFF
+        ],
+    );
+    let console = BufferConsole::new();
+    vm.set_console(Box::new(console.clone()));
+    let mut output = console.clone();
+    vm.register_trap(0x30, move |vm| {
+        let number = format!("{}", vm.register(Register::R0) as i16);
+        output.write(number.as_bytes());
+        Ok(())
+    });
+    vm.register_trap(TrapCode::Out as u8, |vm| {
+        vm.set_register(Register::R1, vm.register(Register::R0));
+        Ok(())
+    });
+    vm.register_trap(0x31, |vm| {
+        vm.halt();
+        Ok(())
+    });
+    assert_eq!(vm.run(), StopReason::Halted);
+    assert_eq!(console.take_output(), b"15");
+    assert_eq!(vm.register(Register::R1), 15);
+
+    // vectors without a handler still go through the table
+    vm.set_register(Register::PC, 0x3004);
+    match vm.run() {
+        StopReason::Fault(fault) => assert_eq!(fault.error, VmError::UnknownTrap(0xFF)),
+        other => panic!("unexpected stop reason {:?}", other),
+    }
+}
+
+#[test]
+fn test_exit_status() {
+    let mut vm = vm_with_program(
+        0x3000,
+        &[
+            0b0001_000_000_1_00011, // ADD R0, R0, #3
+            0xF026,                 // EXIT
+            0xF025,                 // HALT
+        ],
+    );
+    vm.set_console(Box::new(crate::console::BufferConsole::new()));
+    assert_eq!(vm.exit_status(), 0);
+    assert_eq!(vm.run(), StopReason::Halted);
+    assert_eq!(vm.exit_status(), 3);
+    assert_eq!(vm.register(Register::PC), 0x3002);
+    vm.read_image(&[0x30, 0x00, 0xF0, 0x25]).unwrap();
+    assert_eq!(vm.exit_status(), 0);
 }
@@ -0,0 +1,337 @@
+use std::path::PathBuf;
+use std::process::Command;
+
+fn memevm() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_memevm"))
+}
+
+/// A fresh directory for the files of one test.
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("memevm-cli-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn test_assemble() {
+    let dir = scratch_dir("assemble");
+    let image = dir.join("hello.obj");
+    let status = memevm()
+        .arg("--assemble")
+        .arg("res/hello_world.asm")
+        .arg(&image)
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let words: Vec<u16> = std::fs::read(&image)
+        .unwrap()
+        .chunks(2)
+        .map(|pair| u16::from(pair[0]) << 8 | u16::from(pair[1]))
+        .collect();
+    assert_eq!(words[..4], [0x3000, 0xE002, 0xF022, 0xF025]);
+    assert_eq!(
+        words[4..],
+        b"Hello World!\0"
+            .iter()
+            .map(|&c| u16::from(c))
+            .collect::<Vec<_>>()[..]
+    );
+
+    let symbols = std::fs::read_to_string(dir.join("hello.sym")).unwrap();
+    assert!(symbols.contains("//\tHELLO_STR         3003\n"));
+
+    let listing = std::fs::read_to_string(dir.join("hello.lst")).unwrap();
+    assert!(listing.contains("\n3000 | E002 | 1110000000000010 | LEA R0, HELLO_STR"));
+    assert!(listing.contains("\n3003 | 0048 | 0000000001001000 | HELLO_STR .STRINGZ"));
+    assert!(listing.ends_with("Symbol table\n3003 | HELLO_STR\n"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_assemble_stdin() {
+    use std::io::Write;
+
+    let dir = scratch_dir("assemble-stdin");
+    let image = dir.join("fill.obj");
+    let mut child = memevm()
+        .arg("--assemble")
+        .arg("-")
+        .arg(&image)
+        .stdin(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b".ORIG x3000\n.FILL x1234\n.END\n")
+        .unwrap();
+    assert!(child.wait().unwrap().success());
+    assert_eq!(std::fs::read(&image).unwrap(), [0x30, 0x00, 0x12, 0x34]);
+
+    // there is no source file to name the image after
+    let output = memevm()
+        .arg("--assemble")
+        .arg("-")
+        .stdin(std::process::Stdio::null())
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_assemble_errors() {
+    let dir = scratch_dir("assemble-errors");
+    let source = dir.join("bad.asm");
+    std::fs::write(&source, ".ORIG x3000\nLEA R0, NOWHERE\n.END\n").unwrap();
+    let output = memevm().arg("--assemble").arg(&source).output().unwrap();
+    assert_eq!(output.status.code(), Some(65));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("NOWHERE"));
+    assert!(!dir.join("bad.obj").exists());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_link() {
+    let dir = scratch_dir("link");
+    std::fs::write(
+        dir.join("main.asm"),
+        "        .ORIG x3000
+        .EXTERNAL PRINT
+        LEA R0, HELLO
+        JSR PRINT
+        HALT
+HELLO   .STRINGZ \"linked\"
+        .END
+",
+    )
+    .unwrap();
+    // assembled at x0000, so it only works if the linker moves it where it is asked to
+    std::fs::write(
+        dir.join("print.asm"),
+        "        .ORIG x0000
+        .GLOBAL PRINT
+PRINT   PUTS
+        RET
+        .END
+",
+    )
+    .unwrap();
+    for source in &["main.asm", "print.asm"] {
+        let status = memevm()
+            .arg("--object")
+            .arg(dir.join(source))
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+    let image = dir.join("linked.obj");
+    let status = memevm()
+        .arg("--link")
+        .arg(&image)
+        .arg(dir.join("main.o"))
+        .arg(format!("{}@x3010", dir.join("print.o").display()))
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let symbols = std::fs::read_to_string(dir.join("linked.sym")).unwrap();
+    assert!(symbols.contains("//\tPRINT             3010\n"));
+
+    let output = memevm().arg(&image).output().unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("linked"));
+
+    let output = memevm()
+        .arg("--link")
+        .arg(dir.join("alone.obj"))
+        .arg(dir.join("main.o"))
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(65));
+    assert!(!dir.join("alone.obj").exists());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_boot_os() {
+    let dir = scratch_dir("os");
+    // OUT prints every character twice, so it shows whose routine ran
+    std::fs::write(
+        dir.join("os.asm"),
+        "        .ORIG x0000
+        .BLKW x21
+        .FILL OUT_ROUTINE
+        .BLKW 3
+        .FILL HALT_ROUTINE
+        .BLKW x1DA
+START   LD R6, SSP
+        LD R0, USER_PSR
+        STR R0, R6, #-1
+        LD R0, USER_PC
+        STR R0, R6, #-2
+        ADD R6, R6, #-2
+        RTI
+SSP      .FILL x3000
+USER_PSR .FILL x8000
+USER_PC  .FILL x3000
+OUT_ROUTINE
+        STI R0, DDR_ADDR
+        STI R0, DDR_ADDR
+        RTI
+DDR_ADDR .FILL xFE06
+HALT_ROUTINE
+        AND R0, R0, #0
+        STI R0, MCR_ADDR
+MCR_ADDR .FILL xFFFE
+        .END
+",
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("program.asm"),
+        "        .ORIG x3000
+        LD R0, CHAR
+        OUT
+        HALT
+CHAR    .FILL x21
+        .END
+",
+    )
+    .unwrap();
+    for source in &["os.asm", "program.asm"] {
+        let status = memevm()
+            .arg("--assemble")
+            .arg(dir.join(source))
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    let output = memevm()
+        .arg(dir.join("program.obj"))
+        .env("MEMEVM_OS", dir.join("os.obj"))
+        .env("MEMEVM_INSTRUCTION_LIMIT", "100")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("!!"));
+
+    let output = memevm()
+        .arg(dir.join("program.obj"))
+        .env("MEMEVM_OS", dir.join("os.obj"))
+        .env("MEMEVM_TRAPS", "native")
+        .env("MEMEVM_INSTRUCTION_LIMIT", "100")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains('!') && !stdout.contains("!!"));
+
+    let output = memevm()
+        .arg(dir.join("program.obj"))
+        .env("MEMEVM_OS", dir.join("missing.obj"))
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(66));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_scripted_input() {
+    let dir = scratch_dir("input");
+    std::fs::write(
+        dir.join("echo.asm"),
+        "        .ORIG x3000
+        GETC
+        OUT
+        GETC
+        OUT
+        HALT
+        .END
+",
+    )
+    .unwrap();
+    std::fs::write(dir.join("keys"), "ok").unwrap();
+    let output = memevm()
+        .arg(dir.join("echo.asm"))
+        .env("MEMEVM_INPUT", dir.join("keys"))
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("ok"));
+
+    // the keys run out before the program is done
+    std::fs::write(dir.join("keys"), "o").unwrap();
+    let output = memevm()
+        .arg(dir.join("echo.asm"))
+        .env("MEMEVM_INPUT", dir.join("keys"))
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(70));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_breakpoints() {
+    let dir = scratch_dir("break");
+    std::fs::write(
+        dir.join("count.asm"),
+        "        .ORIG x3000
+        AND R1, R1, #0
+LOOP    ADD R1, R1, #1
+        ADD R2, R1, #-3
+        BRn LOOP
+        HALT
+        .END
+",
+    )
+    .unwrap();
+    let output = memevm()
+        .arg(dir.join("count.asm"))
+        .env("MEMEVM_BREAK", "LOOP, x3004")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    // each breakpoint reports only the first time it is reached
+    assert_eq!(stderr.matches("breakpoint at x3001").count(), 1);
+    assert!(stderr.contains("R1: x0000"));
+    assert!(stderr.contains("breakpoint at x3004"));
+    assert!(stderr.contains("R1: x0003"));
+
+    let output = memevm()
+        .arg(dir.join("count.asm"))
+        .env("MEMEVM_BREAK", "NOWHERE")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(66));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_log_goes_to_stderr() {
+    let output = memevm()
+        .arg("res/hello_world.asm")
+        .env("MEMEVM_LOG", "debug")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.starts_with("Hello World!"), "{}", stdout);
+    assert!(!stdout.contains("MAP"));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("MAP"));
+
+    // info is the default level
+    let output = memevm().arg("res/hello_world.asm").output().unwrap();
+    assert!(!String::from_utf8_lossy(&output.stderr).contains("MAP"));
+}